@@ -0,0 +1,69 @@
+//! Compares the thread pools of `kvs-server` under the same workload: clients writing then reading keys
+//! concurrently, each over its own connection. Run with `cargo bench`.
+
+use kvs::{
+    KvStore, KvsClient, KvsServer, NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool,
+    ThreadPool,
+};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const CLIENTS: usize = 16;
+const KEYS_PER_CLIENT: usize = 500;
+
+fn main() -> Result<()> {
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    println!(
+        "{} clients, {} keys each, {} threads",
+        CLIENTS, KEYS_PER_CLIENT, threads
+    );
+    report("naive", bench(NaiveThreadPool::new(threads)?)?);
+    report("shared-queue", bench(SharedQueueThreadPool::new(threads)?)?);
+    report("rayon", bench(RayonThreadPool::new(threads)?)?);
+    Ok(())
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let ops = (CLIENTS * KEYS_PER_CLIENT * 2) as f64;
+    println!(
+        "{:<12} {:>8.1?} {:>10.0} ops/s",
+        name,
+        elapsed,
+        ops / elapsed.as_secs_f64()
+    );
+}
+
+// Serves a new store with `pool`, returning how long the clients took.
+fn bench(pool: impl ThreadPool + Send + 'static) -> Result<Duration> {
+    let temp_dir = TempDir::new()?;
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).thread_pool(pool).serve(listener));
+
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|client_id| thread::spawn(move || run_client(addr, client_id)))
+        .collect();
+    for client in clients {
+        client.join().unwrap()?;
+    }
+    Ok(start.elapsed())
+}
+
+fn run_client(addr: SocketAddr, client_id: usize) -> Result<()> {
+    let mut client = KvsClient::connect(addr)?;
+    for key_id in 0..KEYS_PER_CLIENT {
+        client.set(
+            format!("key{}-{}", client_id, key_id),
+            format!("value{}", key_id),
+        )?;
+    }
+    for key_id in 0..KEYS_PER_CLIENT {
+        let value = client.get(format!("key{}-{}", client_id, key_id))?;
+        assert_eq!(value, Some(format!("value{}", key_id)));
+    }
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use anyhow::Context;
+use clap::{App, AppSettings, Arg, SubCommand};
+use kvs::{KvsClient, Result};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() {
+    let addr = Arg::with_name("addr")
+        .long("addr")
+        .value_name("IP:PORT")
+        .default_value(DEFAULT_ADDR)
+        .help("the address of the server");
+    let matches = App::new("kvs-client")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("talk to a kvs-server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("get value by KEY")
+                .arg(
+                    Arg::with_name("KEY")
+                        .required(true)
+                        .index(1)
+                        .help("the key to look up"),
+                )
+                .arg(addr.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("set KEY to VALUE")
+                .arg(
+                    Arg::with_name("KEY")
+                        .required(true)
+                        .index(1)
+                        .help("the key to set"),
+                )
+                .arg(
+                    Arg::with_name("VALUE")
+                        .required(true)
+                        .index(2)
+                        .help("the value to set KEY to"),
+                )
+                .arg(addr.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("remove value by KEY")
+                .arg(
+                    Arg::with_name("KEY")
+                        .required(true)
+                        .index(1)
+                        .help("the key to remove"),
+                )
+                .arg(addr),
+        )
+        .get_matches();
+
+    std::process::exit(match handle_args(&matches) {
+        Ok(_) => 0,
+        Err(err) => {
+            eprintln!("error: {:#}", err);
+            1
+        }
+    })
+}
+
+fn handle_args(matches: &clap::ArgMatches) -> Result<()> {
+    let (name, matches) = match matches.subcommand() {
+        (name, Some(matches)) => (name, matches),
+        _ => unreachable!("a subcommand is required"),
+    };
+    let addr = matches.value_of("addr").context("Getting addr value")?;
+    let key = matches.value_of("KEY").context("Getting KEY value")?;
+    let mut client = KvsClient::connect(addr)?;
+    match name {
+        "get" => match client.get(key.to_owned())? {
+            Some(value) => println!("{}", value),
+            None => println!("Key not found"),
+        },
+        "set" => {
+            let value = matches.value_of("VALUE").context("Getting VALUE value")?;
+            client.set(key.to_owned(), value.to_owned())?;
+        }
+        "rm" => client.remove(key.to_owned())?,
+        _ => unreachable!("unknown subcommand {}", name),
+    }
+    Ok(())
+}
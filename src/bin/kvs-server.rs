@@ -0,0 +1,120 @@
+use anyhow::{bail, Context};
+use clap::{App, Arg};
+use kvs::{
+    KvStore, KvsServer, NaiveThreadPool, Protocol, RayonThreadPool, Result, SharedQueueThreadPool,
+    ThreadPool, DEFAULT_IDLE_TIMEOUT,
+};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+fn main() {
+    let matches = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("serve a key-value store over TCP")
+        .arg(
+            Arg::with_name("addr")
+                .long("addr")
+                .value_name("IP:PORT")
+                .default_value(DEFAULT_ADDR)
+                .help("the address to listen on"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .long("dir")
+                .value_name("DIR")
+                .default_value(".")
+                .help("the directory of the store"),
+        )
+        .arg(
+            Arg::with_name("protocol")
+                .long("protocol")
+                .value_name("PROTOCOL")
+                .possible_values(&["kvs", "resp", "http"])
+                .default_value("kvs")
+                .help(
+                    "the protocol to speak: kvs (for kvs-client), resp (for Redis clients) or http",
+                ),
+        )
+        .arg(
+            Arg::with_name("thread-pool")
+                .long("thread-pool")
+                .value_name("POOL")
+                .possible_values(&["naive", "shared-queue", "rayon"])
+                .default_value("naive")
+                .help("the thread pool connections are handled on: naive starts a thread per connection"),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("N")
+                .help("the number of threads of the pool (the number of CPUs by default)"),
+        )
+        .arg(
+            Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .help("close connections which send no request for that long (0 for never, the default of the naive pool, 10 for the others)"),
+        )
+        .get_matches();
+
+    std::process::exit(match handle_args(&matches) {
+        Ok(_) => 0,
+        Err(err) => {
+            eprintln!("error: {:?}", err);
+            1
+        }
+    })
+}
+
+fn handle_args(matches: &clap::ArgMatches) -> Result<()> {
+    let addr = matches.value_of("addr").context("Getting addr value")?;
+    let dir = matches.value_of("dir").context("Getting dir value")?;
+    let protocol = match matches.value_of("protocol") {
+        Some("resp") => Protocol::Resp,
+        Some("http") => Protocol::Http,
+        _ => Protocol::Kvs,
+    };
+    let threads = match matches.value_of("threads") {
+        Some(threads) => threads.parse().context("Parsing threads value")?,
+        None => thread::available_parallelism().map_or(1, |threads| threads.get()),
+    };
+    if threads == 0 {
+        bail!("The thread pool needs at least one thread");
+    }
+    let pool_name = matches
+        .value_of("thread-pool")
+        .context("Getting thread-pool value")?;
+    let idle_timeout = match matches.value_of("idle-timeout") {
+        Some(seconds) => match seconds.parse().context("Parsing idle-timeout value")? {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
+        // a naive pool has a thread for each connection, so idle ones keep no other connection waiting
+        None if pool_name == "naive" => None,
+        None => Some(DEFAULT_IDLE_TIMEOUT),
+    };
+    let kv_store = KvStore::open(dir)?;
+    eprintln!(
+        "kvs-server {} serving {:?} on {} ({:?} protocol, {} thread pool)",
+        env!("CARGO_PKG_VERSION"),
+        dir,
+        addr,
+        protocol,
+        pool_name
+    );
+    let server = KvsServer::new(kv_store)
+        .protocol(protocol)
+        .idle_timeout(idle_timeout);
+    match pool_name {
+        "shared-queue" => run(server, SharedQueueThreadPool::new(threads)?, addr),
+        "rayon" => run(server, RayonThreadPool::new(threads)?, addr),
+        _ => run(server, NaiveThreadPool::new(threads)?, addr),
+    }
+}
+
+fn run(server: KvsServer, pool: impl ThreadPool, addr: &str) -> Result<()> {
+    server.thread_pool(pool).run(addr)
+}
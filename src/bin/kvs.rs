@@ -1,6 +1,6 @@
 use anyhow::Context;
 use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::{KvStore, Result};
+use kvs::{KvStore, KvsEngine, Result};
 use std::io;
 
 fn main() {
@@ -43,6 +43,10 @@ fn main() {
                         .help("the key to remove"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("rewrite log files written in an older on-disk format into the current one"),
+        )
         .subcommand(
             SubCommand::with_name("interactive")
                 .about("Run in interactive mode, allowing multiple commands"),
@@ -59,21 +63,34 @@ fn main() {
 }
 
 fn handle_args(matches: &clap::ArgMatches) -> Result<()> {
-    let mut kv_store = KvStore::open(".")?;
+    if matches.subcommand_matches("migrate").is_some() {
+        KvStore::open(".")?.migrate()?;
+        return Ok(());
+    }
+    // looking up a key never needs to modify the store
+    let kv_store = if matches.subcommand_matches("get").is_some() {
+        KvStore::open_read_only(".")?
+    } else {
+        KvStore::open(".")?
+    };
+    run(&kv_store, matches)
+}
+
+fn run(kv_store: &impl KvsEngine, matches: &clap::ArgMatches) -> Result<()> {
     if let Some(matches) = matches.subcommand_matches("get") {
         let key = matches.value_of("KEY").context("Getting KEY value")?;
-        handle_get(&mut kv_store, key)?;
+        handle_get(kv_store, key)?;
     }
 
     if let Some(matches) = matches.subcommand_matches("set") {
         let key = matches.value_of("KEY").context("Getting KEY value")?;
         let value = matches.value_of("VALUE").context("Getting VALUE value")?;
-        handle_set(&mut kv_store, key, value)?;
+        handle_set(kv_store, key, value)?;
     }
 
     if let Some(matches) = matches.subcommand_matches("rm") {
         let key = matches.value_of("KEY").context("Getting KEY value")?;
-        handle_rm(&mut kv_store, key)?;
+        handle_rm(kv_store, key)?;
     }
 
     if matches.subcommand_matches("interactive").is_some() {
@@ -85,23 +102,23 @@ fn handle_args(matches: &clap::ArgMatches) -> Result<()> {
             let split: Vec<_> = buffer.split(' ')
                 .map(|str| str.trim())
                 .collect();
-            match split.get(0).cloned() {
+            match split.first().cloned() {
                 None => break,
                 Some("") => break,
                 Some("exit") => break,
                 // TODO: better error handling for missing args
                 Some("get") => {
                     let key = split.get(1).context("Getting KEY value")?;
-                    handle_get(&mut kv_store, key)?;
+                    handle_get(kv_store, key)?;
                 },
                 Some("set") => {
                     let key = split.get(1).context("Getting KEY value")?;
                     let value = split.get(2).context("Getting VALUE value")?;
-                    handle_set(&mut kv_store, key, value)?;
+                    handle_set(kv_store, key, value)?;
                 },
                 Some("rm") => {
                     let key = split.get(1).context("Getting KEY value")?;
-                    handle_rm(&mut kv_store, key)?;
+                    handle_rm(kv_store, key)?;
                 },
                 Some(_) => println!("unknown command"),
             }
@@ -111,7 +128,7 @@ fn handle_args(matches: &clap::ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn handle_get(kv_store: &mut KvStore, key: &str) -> Result<()> {
+fn handle_get(kv_store: &impl KvsEngine, key: &str) -> Result<()> {
     if let Some(value) = kv_store.get(key.to_owned())? {
         println!("{}", value);
     } else {
@@ -121,12 +138,12 @@ fn handle_get(kv_store: &mut KvStore, key: &str) -> Result<()> {
     Ok(())
 }
 
-fn handle_set(kv_store: &mut KvStore, key: &str, value: &str) -> Result<()> {
+fn handle_set(kv_store: &impl KvsEngine, key: &str, value: &str) -> Result<()> {
     kv_store.set(key.to_owned(), value.to_owned())?;
     Ok(())
 }
 
-fn handle_rm(kv_store: &mut KvStore, key: &str) -> Result<()> {
+fn handle_rm(kv_store: &impl KvsEngine, key: &str) -> Result<()> {
     kv_store.remove(key.to_owned())?;
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,58 @@
+use super::command::Command;
+use super::protocol::{read_message, write_message, Request, Response};
+use super::Result;
+use anyhow::{bail, Context};
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+
+/// KvsClient talks to a `KvsServer` over TCP. Requests are sent one at a time over a single connection.
+#[derive(Debug)]
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connects to the server listening on `addr`.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = TcpStream::connect(addr).context("Connecting to server")?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Set a `value` for `key`. If `key` was already present, the new `value` will override it.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(&Request::Write(Command::Set {
+            key: key.into_bytes(),
+            value: value.into_bytes(),
+        }))?;
+        Ok(())
+    }
+
+    /// Get Some(value) from the server, searching by `key`. If the `key` is not present, None will be returned.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        let value = self.request(&Request::Get {
+            key: key.into_bytes(),
+        })?;
+        Ok(value.map(String::from_utf8).transpose()?)
+    }
+
+    /// Removes `key` from the server's store. This will throw an error if the `key` does not already exist.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(&Request::Write(Command::Remove {
+            key: key.into_bytes(),
+        }))?;
+        Ok(())
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Option<Vec<u8>>> {
+        write_message(&mut self.writer, request)?;
+        match read_message(&mut self.reader)? {
+            Some(Response::Ok(value)) => Ok(value),
+            Some(Response::Err(message)) => bail!("{}", message),
+            None => bail!("Server closed the connection"),
+        }
+    }
+}
@@ -1,28 +1,159 @@
+use super::encryption::Cipher;
+use super::namespace::DEFAULT_NAMESPACE;
+use super::record::{read_framed, write_compressed_record, Codec};
+use super::Result;
+use anyhow::bail;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
 use std::io::Read;
 use std::io::Write;
-use serde::{Deserialize, Serialize};
-use bincode::{serialize_into, deserialize_from};
-use super::Result;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+/// Keys and values are arbitrary bytes. These are encoded just like Strings were, so logs written
+/// with String keys and values can still be read.
+///
+/// New variants must be added at the end, since bincode encodes the variant index.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    /// Several commands written as a single record, so that replay applies either all of them or none.
+    Batch { commands: Vec<Command> },
+    /// A set of a key which expires at `expires_at`, in milliseconds since the Unix epoch.
+    SetWithExpiry {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+    /// A command of the transaction `txn`, which is only applied once the transaction's commit marker follows it.
+    TransactionWrite { txn: u64, command: Box<Command> },
+    /// The commit marker of the transaction `txn`.
+    TransactionCommit { txn: u64 },
+    /// Creates the namespace `id`, named `name`.
+    CreateNamespace { id: u32, name: String },
+    /// Drops the namespace `id`, with all of its keys.
+    DropNamespace { id: u32 },
+    /// A command applied to the namespace `namespace` (commands of the default namespace are not wrapped).
+    Namespaced {
+        namespace: u32,
+        command: Box<Command>,
+    },
 }
 
 impl Command {
-    pub fn to_writer<W>(&self, writer: W) -> Result<()>
+    /// Creates a set of `key` to `value`, which expires at `expires_at` if given.
+    pub fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Self {
+        match expires_at {
+            Some(expires_at) => Command::SetWithExpiry {
+                key,
+                value,
+                expires_at,
+            },
+            None => Command::Set { key, value },
+        }
+    }
+
+    /// Wraps `command` into a `Namespaced` command, unless `namespace` is the default namespace.
+    pub fn in_namespace(namespace: u32, command: Command) -> Self {
+        if namespace == DEFAULT_NAMESPACE {
+            command
+        } else {
+            Command::Namespaced {
+                namespace,
+                command: Box::new(command),
+            }
+        }
+    }
+
+    /// The namespace whose keys the command writes to.
+    pub fn namespace(&self) -> u32 {
+        match self {
+            Command::Namespaced { namespace, .. } => *namespace,
+            Command::TransactionWrite { command, .. } => command.namespace(),
+            _ => DEFAULT_NAMESPACE,
+        }
+    }
+
+    /// Writes the command as a single framed record encoded by `codec`, returning the number of bytes written.
+    pub fn to_writer<W>(&self, writer: W, codec: &Codec) -> Result<u64>
     where
         W: Write,
     {
-        serialize_into(writer, &self)?;
-        Ok(())
+        write_compressed_record(writer, self, codec)
+    }
+
+    /// Reads a single framed command, failing if it is incomplete or its checksum doesn't match.
+    pub fn from_reader<R>(reader: R, cipher: &Cipher) -> Result<Self>
+    where
+        R: Read,
+    {
+        read_framed(reader, cipher)
+    }
+
+    /// Reads a single command of a log written before records were framed (format version 0), which is plain
+    /// bincode, reading at most `limit` bytes. Only `Set` and `Remove` existed then.
+    pub fn from_legacy_reader<R>(reader: R, limit: u64) -> Result<Self>
+    where
+        R: Read,
+    {
+        // the options bincode::deserialize_from used, but with a limit so that garbage can't claim a huge length
+        let cmd: Command = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(limit)
+            .deserialize_from(reader)?;
+        match cmd {
+            Command::Set { .. } | Command::Remove { .. } => Ok(cmd),
+            _ => bail!("Unexpected command in a log of format version 0"),
+        }
+    }
+
+    /// Returns the value this command leaves `key` with: Some(value) if it sets `key`, None if it removes it
+    /// (or doesn't touch it). In a batch, the last command touching `key` wins.
+    pub fn value_of(self, key: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Command::Set { key: k, value } | Command::SetWithExpiry { key: k, value, .. }
+                if k == key =>
+            {
+                Some(value)
+            }
+            Command::Set { .. } | Command::SetWithExpiry { .. } | Command::Remove { .. } => None,
+            Command::Batch { commands } => commands
+                .into_iter()
+                .rev()
+                .find(|cmd| cmd.touches(key))
+                .and_then(|cmd| cmd.value_of(key)),
+            Command::TransactionWrite { command, .. } | Command::Namespaced { command, .. } => {
+                command.value_of(key)
+            }
+            Command::TransactionCommit { .. }
+            | Command::CreateNamespace { .. }
+            | Command::DropNamespace { .. } => None,
+        }
     }
 
-    pub fn from_reader<R>(reader: R) -> Result<Self> where
-    R: Read,
-     {
-        let cmd: Command = deserialize_from(reader)?;
-        Ok(cmd)
+    /// Whether this command sets or removes `key`.
+    fn touches(&self, key: &[u8]) -> bool {
+        match self {
+            Command::Set { key: k, .. }
+            | Command::SetWithExpiry { key: k, .. }
+            | Command::Remove { key: k } => k == key,
+            Command::Batch { commands } => commands.iter().any(|cmd| cmd.touches(key)),
+            Command::TransactionWrite { command, .. } | Command::Namespaced { command, .. } => {
+                command.touches(key)
+            }
+            Command::TransactionCommit { .. }
+            | Command::CreateNamespace { .. }
+            | Command::DropNamespace { .. } => false,
+        }
     }
 }
@@ -0,0 +1,82 @@
+use super::{Compression, Result};
+use anyhow::bail;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// zstd's own default, a good balance between speed and ratio
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compressor compresses the payload of log records with the `Compression` a store was opened with,
+/// and keeps track of how much space it saved.
+#[derive(Debug)]
+pub struct Compressor {
+    compression: Compression,
+    // payload bytes before and after compression, since the store was opened
+    raw_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            raw_bytes: AtomicU64::new(0),
+            stored_bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// Compresses `payload`, returning the compression it ended up stored with:
+    /// a payload which doesn't get any smaller is stored uncompressed.
+    pub fn compress(&self, payload: Vec<u8>) -> Result<(Compression, Vec<u8>)> {
+        let compressed = match self.compression {
+            Compression::None => None,
+            Compression::Lz4 => Some(lz4_flex::compress_prepend_size(&payload)),
+            Compression::Zstd => Some(zstd::bulk::compress(&payload, ZSTD_LEVEL)?),
+        };
+        self.raw_bytes
+            .fetch_add(payload.len() as u64, Ordering::Relaxed);
+        let (compression, stored) = match compressed {
+            Some(compressed) if compressed.len() < payload.len() => (self.compression, compressed),
+            _ => (Compression::None, payload),
+        };
+        self.stored_bytes
+            .fetch_add(stored.len() as u64, Ordering::Relaxed);
+        Ok((compression, stored))
+    }
+
+    /// Ratio of the payload bytes compressed so far to the bytes they were stored as (1.0 if nothing was written).
+    pub fn ratio(&self) -> f64 {
+        let stored_bytes = self.stored_bytes.load(Ordering::Relaxed);
+        if stored_bytes == 0 {
+            return 1.0;
+        }
+        self.raw_bytes.load(Ordering::Relaxed) as f64 / stored_bytes as f64
+    }
+}
+
+/// Decompresses a payload stored with `compression`.
+pub fn decompress(compression: Compression, payload: Vec<u8>) -> Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(payload),
+        Compression::Lz4 => Ok(lz4_flex::decompress_size_prepended(&payload)?),
+        Compression::Zstd => Ok(zstd::stream::decode_all(payload.as_slice())?),
+    }
+}
+
+/// Flag recorded in the header of a record stored with `compression`.
+pub fn to_flag(compression: Compression) -> u32 {
+    match compression {
+        Compression::None => 0,
+        Compression::Lz4 => 1,
+        Compression::Zstd => 2,
+    }
+}
+
+/// Compression of a record whose header holds `flag`.
+pub fn from_flag(flag: u32) -> Result<Compression> {
+    match flag {
+        0 => Ok(Compression::None),
+        1 => Ok(Compression::Lz4),
+        2 => Ok(Compression::Zstd),
+        _ => bail!("Unknown compression flag {}", flag),
+    }
+}
@@ -0,0 +1,128 @@
+use super::Result;
+use anyhow::bail;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::BTreeMap;
+use std::fmt;
+
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+
+/// A 256 bit encryption key, along with the id recorded next to the records it encrypts.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    pub id: u32,
+    pub bytes: [u8; 32],
+}
+
+impl fmt::Debug for EncryptionKey {
+    // never print the key itself
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Cipher encrypts record payloads with ChaCha20-Poly1305, using the encryption key a store was opened with.
+/// It can decrypt payloads encrypted with any of the keys the store was opened with, so that a key can be
+/// rotated: compaction rewrites the records encrypted with previous keys using the new one.
+///
+/// An encrypted payload is made of the id of its key (little endian u32), a random nonce and the ciphertext.
+pub struct Cipher {
+    encryption_key: Option<u32>,
+    keys: BTreeMap<u32, ChaCha20Poly1305>,
+    rejects_plaintext: bool,
+}
+
+impl Cipher {
+    pub fn new(encryption_key: Option<&EncryptionKey>, decryption_keys: &[EncryptionKey]) -> Self {
+        let keys = encryption_key
+            .into_iter()
+            .chain(decryption_keys)
+            .map(|key| {
+                let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.bytes));
+                (key.id, cipher)
+            })
+            .collect();
+        Self {
+            encryption_key: encryption_key.map(|key| key.id),
+            keys,
+            rejects_plaintext: false,
+        }
+    }
+
+    /// Makes payloads which weren't encrypted fail to be read, once every record of the store is encrypted.
+    pub fn reject_plaintext(&mut self) {
+        self.rejects_plaintext = true;
+    }
+
+    /// Whether payloads which weren't encrypted fail to be read.
+    pub fn rejects_plaintext(&self) -> bool {
+        self.rejects_plaintext
+    }
+
+    /// Whether payloads are encrypted before being written.
+    pub fn is_enabled(&self) -> bool {
+        self.encryption_key.is_some()
+    }
+
+    /// Encrypts `payload` with the encryption key, authenticating `aad` along with it.
+    pub fn encrypt(&self, aad: &[u8], payload: &[u8]) -> Result<Vec<u8>> {
+        let id = match self.encryption_key {
+            Some(id) => id,
+            None => bail!("No encryption key was given"),
+        };
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = match self.keys[&id].encrypt(&nonce, Payload { msg: payload, aad }) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => bail!("Encrypting record with key {}", id),
+        };
+        let mut encrypted = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+        encrypted.extend_from_slice(&id.to_le_bytes());
+        encrypted.extend_from_slice(&nonce);
+        encrypted.extend_from_slice(&ciphertext);
+        Ok(encrypted)
+    }
+
+    /// Decrypts a payload written by `encrypt`, failing if it wasn't encrypted by one of the keys,
+    /// or if it (or `aad`) was tampered with.
+    pub fn decrypt(&self, aad: &[u8], encrypted: &[u8]) -> Result<Vec<u8>> {
+        if encrypted.len() < KEY_ID_LEN + NONCE_LEN {
+            bail!("Encrypted record is too short");
+        }
+        let (id, rest) = encrypted.split_at(KEY_ID_LEN);
+        let id = u32::from_le_bytes([id[0], id[1], id[2], id[3]]);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let cipher = match self.keys.get(&id) {
+            Some(cipher) => cipher,
+            None if self.keys.is_empty() => {
+                bail!("Store is encrypted, but no encryption key was given")
+            }
+            None => bail!("Record is encrypted with key {}, which was not given", id),
+        };
+        match cipher.decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        ) {
+            Ok(payload) => Ok(payload),
+            Err(_) => bail!(
+                "Wrong encryption key: record can't be decrypted with key {}",
+                id
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher")
+            .field("encryption_key", &self.encryption_key)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("rejects_plaintext", &self.rejects_plaintext)
+            .finish()
+    }
+}
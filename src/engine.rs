@@ -0,0 +1,18 @@
+use super::Result;
+
+/// Trait for a key-value storage engine.
+///
+/// `KvStore` is the default implementation, but anything implementing this trait can be
+/// plugged in wherever a storage engine is needed (e.g. an in-memory engine for tests).
+///
+/// Engines must be safe to share between threads, so all operations take `&self`.
+pub trait KvsEngine: Send + Sync {
+    /// Set a `value` for `key`. If `key` was already present, the new `value` will override it.
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// Get Some(value) from the engine, searching by `key`. If the `key` is not present, None will be returned.
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// Removes `key` from the engine. This will throw an error if the `key` does not already exist.
+    fn remove(&self, key: String) -> Result<()>;
+}
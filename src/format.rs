@@ -0,0 +1,53 @@
+use super::Result;
+use anyhow::bail;
+use std::io::{Read, Write};
+
+/// Every log (and hint) file starts with a header made of magic bytes and the version of the format of its records
+/// (a little endian u32), so that a store written by a newer version of kvs is never misread.
+///
+/// Files written before the header existed are format version 0: plain bincode `Set` and `Remove` commands,
+/// one after the other, without any framing. `KvStore::migrate` rewrites them into the current format.
+/// Their first bytes can't be mistaken for the magic bytes, since they are the variant index of a command (0 or 1).
+const MAGIC: [u8; 4] = *b"\x89KVS";
+pub const FILE_HEADER_LEN: u64 = 8;
+
+/// Version of the format of the files written by this version of kvs.
+pub const FORMAT_VERSION: u32 = 1;
+/// Version of the files written before they had a header, whose records are not framed.
+pub const LEGACY_FORMAT_VERSION: u32 = 0;
+
+/// Writes the header of a new file, returning the number of bytes written.
+pub fn write_file_header<W>(mut writer: W) -> Result<u64>
+where
+    W: Write,
+{
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    Ok(FILE_HEADER_LEN)
+}
+
+/// Reads the header at the start of a file of `file_len` bytes, failing if the file was written in a newer
+/// format. Returns the format version of the file and the position of its first record, or None if the file is
+/// too short to hold any record, which only happens when a crash interrupted its creation.
+pub fn read_file_header<R>(mut reader: R, file_len: u64) -> Result<Option<(u32, u64)>>
+where
+    R: Read,
+{
+    if file_len < FILE_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    if header[..4] != MAGIC {
+        return Ok(Some((LEGACY_FORMAT_VERSION, 0)));
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if version > FORMAT_VERSION {
+        bail!(
+            "Unsupported format version {}: this version of kvs reads up to version {}",
+            version,
+            FORMAT_VERSION
+        );
+    }
+    Ok(Some((version, FILE_HEADER_LEN)))
+}
@@ -0,0 +1,47 @@
+use super::encryption::Cipher;
+use super::record::write_record;
+use super::Result;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+/// A Hint records where the value of a key lives in a compacted generation.
+///
+/// Like Bitcask hint files, compaction writes one `N.hint` file next to each compacted `N.log`,
+/// so that opening the store can rebuild the in-memory map from the (much smaller) hints instead
+/// of replaying every value of the log. A hint file starts with a `NamespacesHint`, followed by one `Hint` per key.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Hint {
+    #[serde(with = "serde_bytes")]
+    pub key: Vec<u8>,
+    pub generation: u64,
+    pub file_pos: u64,
+    pub estimated_bytes: usize,
+    pub expires_at: Option<u64>,
+    pub namespace: u32,
+}
+
+/// The namespaces created at the start of a compacted generation, which are the first record of its hint file.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct NamespacesHint {
+    pub namespaces: Vec<(u32, String)>,
+}
+
+impl Hint {
+    /// Writes the hint as a single framed record (encrypted by `cipher` if enabled), returning the number of bytes written.
+    pub fn to_writer<W>(&self, writer: W, cipher: &Cipher) -> Result<u64>
+    where
+        W: Write,
+    {
+        write_record(writer, self, cipher)
+    }
+}
+
+impl NamespacesHint {
+    /// Writes the hint as a single framed record (encrypted by `cipher` if enabled), returning the number of bytes written.
+    pub fn to_writer<W>(&self, writer: W, cipher: &Cipher) -> Result<u64>
+    where
+        W: Write,
+    {
+        write_record(writer, self, cipher)
+    }
+}
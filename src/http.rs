@@ -0,0 +1,371 @@
+use super::server::wait_for_request;
+use super::{KvStore, Result};
+use anyhow::{bail, Context};
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::time::Duration;
+
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+/// Number of keys listed by `GET /keys` without a `limit`, and the largest `limit` allowed.
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+
+/// An HTTP request, with its path and query parameters percent-decoded.
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: Vec<u8>,
+    query: HashMap<String, Vec<u8>>,
+    body: Vec<u8>,
+    keep_alive: bool,
+}
+
+/// An HTTP response. Values are sent as is, everything else as JSON.
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    allow: Option<&'static str>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            allow: None,
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: impl std::fmt::Display) -> Self {
+        Self::json(status, json!({ "error": message.to_string() }))
+    }
+
+    fn value(value: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/octet-stream",
+            allow: None,
+            body: value,
+        }
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            content_type: "application/json",
+            allow: None,
+            body: Vec::new(),
+        }
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Self {
+            allow: Some(allow),
+            ..Self::error(405, "Method not allowed")
+        }
+    }
+}
+
+/// Serves a connection of an HTTP/1.1 client, answering each of its requests with the `KvStore`.
+pub fn serve_connection(store: &KvStore, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while wait_for_request(&mut reader)? {
+        let request = match read_request(&mut reader, &mut writer) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                // the rest of the connection can't be parsed, so it is closed
+                write_response(
+                    &mut writer,
+                    &Response::error(400, format!("{:#}", err)),
+                    false,
+                )?;
+                return Err(err);
+            }
+        };
+        let response = match handle(store, &request) {
+            Ok(response) => response,
+            Err(err) => Response::error(500, format!("{:#}", err)),
+        };
+        write_response(&mut writer, &response, request.keep_alive)?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Reads a request, returning None if the connection was closed before it started.
+/// `writer` is only used to tell clients which sent `Expect: 100-continue` to go on with the body.
+fn read_request<R, W>(reader: &mut R, writer: &mut W) -> Result<Option<Request>>
+where
+    R: BufRead,
+    W: Write,
+{
+    let request_line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => bail!("Malformed request line"),
+    };
+    let mut keep_alive = version == "HTTP/1.1";
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    for _ in 0..=MAX_HEADERS {
+        let line = read_line(reader)?.context("Unexpected end of headers")?;
+        if line.is_empty() {
+            let (path, query) = match target.split_once('?') {
+                Some((path, query)) => (path, query),
+                None => (target, ""),
+            };
+            if content_length > MAX_BODY_LEN {
+                bail!("Body of {} bytes is too large", content_length);
+            }
+            if expect_continue && content_length > 0 {
+                writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                writer.flush()?;
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).context("Reading body")?;
+            return Ok(Some(Request {
+                method: method.to_owned(),
+                path: percent_decode(path, false)?,
+                query: parse_query(query)?,
+                body,
+                keep_alive,
+            }));
+        }
+        let (name, value) = line.split_once(':').context("Malformed header")?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => content_length = value.parse().context("Invalid Content-Length")?,
+            "transfer-encoding" => bail!("Transfer-Encoding is not supported, use Content-Length"),
+            "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => {}
+        }
+    }
+    bail!("Too many headers")
+}
+
+/// Reads a line terminated by CRLF (or a lone LF), without its terminator.
+fn read_line<R>(reader: &mut R) -> Result<Option<String>>
+where
+    R: BufRead,
+{
+    let mut line = String::new();
+    reader.take(MAX_LINE_LEN).read_line(&mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        bail!("Line too long");
+    }
+    Ok(Some(line.trim_end_matches(&['\r', '\n'][..]).to_owned()))
+}
+
+fn parse_query(query: &str) -> Result<HashMap<String, Vec<u8>>> {
+    let mut params = HashMap::new();
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let name = String::from_utf8(percent_decode(name, true)?)?;
+        params.insert(name, percent_decode(value, true)?);
+    }
+    Ok(params)
+}
+
+/// Decodes the `%XX` escapes of a path or query component, and the `+` standing for spaces in a query.
+fn percent_decode(encoded: &str, is_query: bool) -> Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [bytes.next(), bytes.next()];
+                let hex = match hex {
+                    [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+                    _ => None,
+                };
+                decoded.push(hex.context("Invalid percent-encoding")?);
+            }
+            b'+' if is_query => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    Ok(decoded)
+}
+
+/// Percent-encodes `bytes`, so that they can be used as a path segment or query value and sent as JSON
+/// whatever they are: only the characters which never need escaping in either are left as is.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' | b'@' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn write_response<W>(writer: &mut W, response: &Response, keep_alive: bool) -> Result<()>
+where
+    W: Write,
+{
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        response.status,
+        reason(response.status),
+        response.body.len()
+    )?;
+    if !response.body.is_empty() {
+        write!(writer, "Content-Type: {}\r\n", response.content_type)?;
+    }
+    if let Some(allow) = response.allow {
+        write!(writer, "Allow: {}\r\n", allow)?;
+    }
+    if !keep_alive {
+        writer.write_all(b"Connection: close\r\n")?;
+    }
+    writer.write_all(b"\r\n")?;
+    writer.write_all(&response.body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Routes a request to the store. Errors of the store are sent back as 500 responses.
+fn handle(store: &KvStore, request: &Request) -> Result<Response> {
+    let method = request.method.as_str();
+    match request.path.as_slice() {
+        b"/health" => match method {
+            "GET" => Ok(Response::json(200, json!({ "status": "ok" }))),
+            _ => Ok(Response::method_not_allowed("GET")),
+        },
+        b"/stats" => match method {
+            "GET" => {
+                let stats = store.stats();
+                Ok(Response::json(
+                    200,
+                    json!({
+                        "keys": stats.keys,
+                        "wasted_bytes": stats.wasted_bytes,
+                        "compacting": stats.compacting,
+                        "sync_policy": format!("{:?}", stats.sync_policy),
+                        "syncs": stats.syncs,
+                        "compression_ratio": stats.compression_ratio,
+                    }),
+                ))
+            }
+            _ => Ok(Response::method_not_allowed("GET")),
+        },
+        b"/keys" | b"/keys/" => match method {
+            "GET" => list_keys(store, &request.query),
+            _ => Ok(Response::method_not_allowed("GET")),
+        },
+        path if path.starts_with(b"/keys/") => {
+            let key = &path[b"/keys/".len()..];
+            match method {
+                "GET" => Ok(match store.get_bytes(key)? {
+                    Some(value) => Response::value(value),
+                    None => Response::error(404, "Key not found"),
+                }),
+                "PUT" => {
+                    match request.query.get("ttl") {
+                        Some(ttl) => {
+                            let ttl = match std::str::from_utf8(ttl)
+                                .ok()
+                                .and_then(|ttl| ttl.parse().ok())
+                            {
+                                Some(ttl) if ttl > 0 => Duration::from_secs(ttl),
+                                _ => {
+                                    return Ok(Response::error(
+                                        400,
+                                        "ttl must be a positive number of seconds",
+                                    ))
+                                }
+                            };
+                            store.set_with_ttl(key, &request.body, ttl)?;
+                        }
+                        None => store.set_bytes(key, &request.body)?,
+                    }
+                    Ok(Response::no_content())
+                }
+                "DELETE" => Ok(match store.get_bytes(key)? {
+                    // a key removed by someone else in between is not found either
+                    Some(value) if store.remove_if_equals(key, &value)? => Response::no_content(),
+                    _ => Response::error(404, "Key not found"),
+                }),
+                _ => Ok(Response::method_not_allowed("GET, PUT, DELETE")),
+            }
+        }
+        _ => Ok(Response::error(404, "Not found")),
+    }
+}
+
+/// Lists the keys starting with the `prefix` parameter, in key order, `limit` at a time. The listing continues
+/// after the key given as `after`, which is the `next` key of the previous page.
+fn list_keys(store: &KvStore, query: &HashMap<String, Vec<u8>>) -> Result<Response> {
+    let prefix = query.get("prefix").map(Vec::as_slice).unwrap_or_default();
+    let limit = match query.get("limit") {
+        Some(limit) => match std::str::from_utf8(limit)
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+        {
+            Some(limit) if (1..=MAX_LIST_LIMIT).contains(&limit) => limit,
+            _ => {
+                return Ok(Response::error(
+                    400,
+                    format!("limit must be between 1 and {}", MAX_LIST_LIMIT),
+                ))
+            }
+        },
+        None => DEFAULT_LIST_LIMIT,
+    };
+    let lower = match query.get("after") {
+        Some(after) if after.as_slice() >= prefix => Bound::Excluded(after.as_slice()),
+        _ => Bound::Included(prefix),
+    };
+    // only the index is walked, values are never read
+    let mut matching = store
+        .scan::<&[u8], _>((lower, Bound::Unbounded))
+        .keys()
+        .take_while(|key| key.starts_with(prefix));
+    let keys: Vec<_> = matching
+        .by_ref()
+        .take(limit)
+        .map(|key| percent_encode(&key))
+        .collect();
+    let next = match matching.next() {
+        Some(_) => keys.last().cloned(),
+        None => None,
+    };
+    Ok(Response::json(200, json!({ "keys": keys, "next": next })))
+}
@@ -0,0 +1,304 @@
+use super::Result;
+use anyhow::bail;
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::mem;
+use std::ops::Bound;
+use std::ops::Bound::{Excluded, Included};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// InternalMap is the in-memory mapping of keys used to save trips to disk.
+/// The values in the map are file offsets used to seek to the true values on disk.
+/// Keys are kept in order, so that ranges of keys can be scanned.
+///
+/// Expired keys are treated as absent by lookups, but stay in the map until `purge_expired` is called.
+///
+/// Every record applied to the map gets a new log sequence number (an in-memory counter, restarting at each open).
+/// A snapshot pins the current sequence number: while it is live, the entries it can see are kept in `history`
+/// when they are overwritten or removed.
+#[derive(Debug)]
+pub struct InternalMap {
+    map: BTreeMap<Vec<u8>, LogEntry>,
+    // keys with an expiry, ordered by expiry time
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+    // sequence number of the latest record applied
+    seq: u64,
+    // sequence numbers of the live snapshots, with how many snapshots are pinned to each
+    snapshots: BTreeMap<u64, usize>,
+    // overwritten or removed entries which a live snapshot may still read
+    history: BTreeMap<Vec<u8>, Vec<Version>>,
+    // generations left behind by compaction, to be removed once no snapshot can read from them
+    obsolete_generations: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    // track which generation log file the value was written to
+    pub generation: u64,
+    // track file offset within that file where we can read the value
+    pub file_pos: u64,
+    // estimate the total bytes necessary to store the key and value to disk
+    // this is used to estimate wasted space eligible for compaction
+    pub estimated_bytes: usize,
+    // time at which the key expires, in milliseconds since the Unix epoch
+    pub expires_at: Option<u64>,
+    // sequence number of the record which wrote the value
+    pub seq: u64,
+}
+
+/// A previous entry of a key, visible to snapshots taken from `entry.seq` until just before `superseded_at`.
+#[derive(Debug)]
+struct Version {
+    entry: LogEntry,
+    superseded_at: u64,
+}
+
+impl LogEntry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Current time in milliseconds since the Unix epoch, the unit of expiry times.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+impl InternalMap {
+    pub fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+            expiries: BTreeSet::new(),
+            seq: 0,
+            snapshots: BTreeMap::new(),
+            history: BTreeMap::new(),
+            obsolete_generations: Vec::new(),
+        }
+    }
+    /// Starts applying a new record, whose entries get the next sequence number.
+    pub fn next_seq(&mut self) {
+        self.seq += 1;
+    }
+    /// Create entry in InternalMap that tracks the LogEntry for this key.
+    /// Returns estimate of wasted bytes detected (if we just overwrote an existing key).
+    pub fn set(
+        &mut self,
+        key: &[u8],
+        generation: u64,
+        file_pos: u64,
+        estimated_bytes: usize,
+        expires_at: Option<u64>,
+    ) -> Result<usize> {
+        let mut wasted_bytes = 0;
+        if let Some(entry_that_will_be_overwritten) = self.map.get(key) {
+            if entry_that_will_be_overwritten.generation > generation {
+                // if incoming write is from a previous generation as the one it is replacing
+                // then exit early (i.e. block the update). This should only ever happen
+                // during compaction.
+                return Ok(0);
+            }
+            wasted_bytes = entry_that_will_be_overwritten.estimated_bytes;
+        }
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert((expires_at, key.to_owned()));
+        }
+        let previous_entry = self.map.insert(
+            key.to_owned(),
+            LogEntry {
+                generation,
+                file_pos,
+                estimated_bytes,
+                expires_at,
+                seq: self.seq,
+            },
+        );
+        self.forget_expiry(key, previous_entry.as_ref());
+        self.keep_version(key, previous_entry);
+        Ok(wasted_bytes)
+    }
+    /// Returns the entry of `key`, unless it is missing or expired.
+    pub fn get(&self, key: &[u8]) -> Result<Option<LogEntry>> {
+        Ok(self.visible(key, None, now_millis()).cloned())
+    }
+    /// Returns the entry of `key` as seen by the snapshot pinned to `seq`, unless it is missing or expired.
+    pub fn get_at(&self, key: &[u8], seq: u64) -> Option<LogEntry> {
+        self.visible(key, Some(seq), now_millis()).cloned()
+    }
+    /// Returns the entry of `key` which is visible at `seq` (or the latest one if None).
+    fn visible(&self, key: &[u8], seq: Option<u64>, now: u64) -> Option<&LogEntry> {
+        let entry = match (self.map.get(key), seq) {
+            (Some(entry), None) => Some(entry),
+            (Some(entry), Some(seq)) if entry.seq <= seq => Some(entry),
+            (_, Some(seq)) => self.history.get(key).and_then(|versions| {
+                versions
+                    .iter()
+                    .find(|version| version.entry.seq <= seq && seq < version.superseded_at)
+                    .map(|version| &version.entry)
+            }),
+            (None, None) => None,
+        };
+        entry.filter(|entry| !entry.is_expired(now))
+    }
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+    /// Remove entry in InternalMap, signifying deletion on disk.
+    /// Returns estimate of wasted bytes detected (if we just removed an existing key).
+    pub fn remove(&mut self, key: &[u8]) -> Result<usize> {
+        let mut wasted_bytes = 0;
+        if let Some(entry_that_will_be_overwritten) = self.map.get(key) {
+            wasted_bytes = entry_that_will_be_overwritten.estimated_bytes;
+        }
+        let removed_entry = self.map.remove(key);
+        if removed_entry.is_none() {
+            bail!("Key not found");
+        }
+        self.forget_expiry(key, removed_entry.as_ref());
+        self.keep_version(key, removed_entry);
+        Ok(wasted_bytes)
+    }
+    /// Removes all the keys, as if each of them was removed. Returns estimate of wasted bytes detected.
+    pub fn clear(&mut self) -> usize {
+        let keys: Vec<_> = self.map.keys().cloned().collect();
+        keys.iter().map(|key| self.remove(key).unwrap_or(0)).sum()
+    }
+    /// Removes all the expired keys from the map, since their values are now wasted space.
+    /// Returns estimate of wasted bytes detected.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = now_millis();
+        let mut wasted_bytes = 0;
+        while let Some((expires_at, _)) = self.expiries.first() {
+            if *expires_at > now {
+                break;
+            }
+            let (_, key) = self.expiries.pop_first().unwrap();
+            if let Some(entry) = self.map.remove(&key) {
+                wasted_bytes += entry.estimated_bytes;
+            }
+        }
+        wasted_bytes
+    }
+    /// Drops the expiry of an entry which was just replaced or removed, unless the new entry has the same one.
+    fn forget_expiry(&mut self, key: &[u8], previous_entry: Option<&LogEntry>) {
+        let previous_expiry = previous_entry.and_then(|entry| entry.expires_at);
+        if let Some(expires_at) = previous_expiry {
+            if self.map.get(key).and_then(|entry| entry.expires_at) != Some(expires_at) {
+                self.expiries.remove(&(expires_at, key.to_owned()));
+            }
+        }
+    }
+    /// Keeps an entry which was just replaced or removed, if a live snapshot can see it.
+    fn keep_version(&mut self, key: &[u8], previous_entry: Option<LogEntry>) {
+        if let Some(entry) = previous_entry {
+            if self.snapshots.range(entry.seq..).next().is_some() {
+                self.history
+                    .entry(key.to_owned())
+                    .or_default()
+                    .push(Version {
+                        entry,
+                        superseded_at: self.seq,
+                    });
+            }
+        }
+    }
+    /// Pins a snapshot to the current sequence number, which is returned.
+    pub fn take_snapshot(&mut self) -> u64 {
+        *self.snapshots.entry(self.seq).or_insert(0) += 1;
+        self.seq
+    }
+    /// Unpins a snapshot taken by `take_snapshot`, dropping the versions no live snapshot can see anymore.
+    /// Returns the generations which can now be removed, if this was the last live snapshot.
+    pub fn release_snapshot(&mut self, seq: u64) -> Vec<u64> {
+        if let btree_map::Entry::Occupied(mut count) = self.snapshots.entry(seq) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+        let snapshots = &self.snapshots;
+        self.history.retain(|_, versions| {
+            versions.retain(|version| {
+                snapshots
+                    .range(version.entry.seq..version.superseded_at)
+                    .next()
+                    .is_some()
+            });
+            !versions.is_empty()
+        });
+        if self.snapshots.is_empty() {
+            mem::take(&mut self.obsolete_generations)
+        } else {
+            Vec::new()
+        }
+    }
+    /// If a snapshot is live, remembers that `generations` must only be removed once it is released,
+    /// since the snapshot may still read from them. Returns whether the removal was deferred.
+    pub fn defer_removal(&mut self, generations: &[u64]) -> bool {
+        if self.snapshots.is_empty() {
+            return false;
+        }
+        self.obsolete_generations.extend_from_slice(generations);
+        true
+    }
+    /// Point `key` at `to`, but only if it still points at `from`.
+    /// Used by compaction, which must not clobber writes or removals that happened while it was running.
+    pub fn relocate(&mut self, key: &[u8], from: &LogEntry, to: LogEntry) {
+        if let Some(entry) = self.map.get_mut(key) {
+            if entry == from {
+                *entry = to;
+            }
+        }
+    }
+    /// Returns the unexpired entries whose latest value lives in a generation before `generation`.
+    pub fn entries_before(&self, generation: u64) -> Vec<(Vec<u8>, LogEntry)> {
+        let now = now_millis();
+        self.map
+            .iter()
+            .filter(|(_, entry)| entry.generation < generation && !entry.is_expired(now))
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
+    }
+    /// Returns the first unexpired key (and its entry) within the given bounds, if any.
+    /// With Some(seq), keys are looked up as seen by the snapshot pinned to `seq`.
+    pub fn first_in_range(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        seq: Option<u64>,
+    ) -> Option<(Vec<u8>, LogEntry)> {
+        // BTreeMap::range panics on inverted ranges, which are simply empty here
+        if let (Included(start) | Excluded(start), Included(end) | Excluded(end)) = (lower, upper) {
+            if start > end || (start == end && matches!(lower, Excluded(_))) {
+                return None;
+            }
+        }
+        let now = now_millis();
+        // keys visible to a snapshot may have been removed since, so previous versions are walked alongside the map
+        let mut latest_keys = self.map.range::<[u8], _>((lower, upper)).peekable();
+        let mut previous_keys = self.history.range::<[u8], _>((lower, upper)).peekable();
+        loop {
+            let key = match (latest_keys.peek(), previous_keys.peek()) {
+                (Some(&(latest, _)), Some(&(previous, _))) if previous < latest => {
+                    previous_keys.next();
+                    previous
+                }
+                (Some(&(latest, _)), previous) => {
+                    if previous.is_some_and(|&(previous, _)| previous == latest) {
+                        previous_keys.next();
+                    }
+                    latest_keys.next();
+                    latest
+                }
+                (None, Some(&(previous, _))) => {
+                    previous_keys.next();
+                    previous
+                }
+                (None, None) => return None,
+            };
+            if let Some(entry) = self.visible(key, seq, now) {
+                return Some((key.clone(), entry.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,1841 @@
+use super::command::Command;
+use super::encryption::Cipher;
+use super::format::{
+    read_file_header, write_file_header, FILE_HEADER_LEN, FORMAT_VERSION, LEGACY_FORMAT_VERSION,
+};
+use super::hint::{Hint, NamespacesHint};
+use super::internal_map::{now_millis, InternalMap, LogEntry};
+use super::lock::DirLock;
+use super::manifest::{sorted_gen_list, Manifest};
+use super::namespace::{Namespaces, DEFAULT_NAMESPACE, DEFAULT_NAMESPACE_NAME};
+use super::record::{read_record, Codec, Record};
+use super::syncer::Syncer;
+use super::{KvsEngine, OpenOptions, Result, Stats, SyncPolicy, Transaction, WriteBatch};
+use anyhow::{bail, Context};
+use std::collections::btree_map;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// A basic key-value store, which stores its keys and values in log files on disk.
+///
+/// A `KvStore` is a cheap handle which can be cloned and shared between threads. All clones share
+/// the same in-memory index and the same (serialized) writer, but every clone owns its own file
+/// handles for reading, so giving each thread its own clone lets reads proceed without contention.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct KvStore {
+    // directory for the log and other data.
+    path: Arc<PathBuf>,
+    // namespace this handle reads and writes
+    namespace: u32,
+    // registry of all the namespaces of the store (shared by all handles)
+    namespaces: Arc<RwLock<Namespaces>>,
+    // internal map used to handle the in-memory storing of the keys of the namespace (shared by all its handles)
+    map: Arc<RwLock<InternalMap>>,
+    // reader handles owned by this particular handle
+    reader: Mutex<KvStoreReader>,
+    // write side of the store, shared by all handles so that writes are serialized (None when read-only)
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    // syncs writes to disk according to the SyncPolicy
+    syncer: Arc<Syncer>,
+    // lock of the store directory, released once the last handle (and so the writer) is dropped
+    _lock: Option<Arc<DirLock>>,
+}
+
+impl KvStore {
+    /// Opens a `KvStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    /// Use `KvStore::builder` to open a store with other options.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    /// It also fails if another process has the store open, naming that process.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_options(path.into(), OpenOptions::new())
+    }
+
+    /// Opens the `KvStore` at `path` read-only, which is the same as `KvStore::builder().read_only(true).open(path)`.
+    ///
+    /// The logs are only replayed: no file is ever created or modified (not even a torn write at the end of a log),
+    /// and compaction never runs. Writes return an error.
+    ///
+    /// # Errors
+    ///
+    /// On top of the errors of `KvStore::open`, this fails if the directory does not exist.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::builder().read_only(true).open(path)
+    }
+
+    /// Returns the `OpenOptions` used to open a `KvStore` with non-default options.
+    pub fn builder() -> OpenOptions {
+        OpenOptions::new()
+    }
+
+    pub(crate) fn open_with_options(path: PathBuf, options: OpenOptions) -> Result<Self> {
+        let path = Arc::new(path);
+        let options = Arc::new(options);
+        if !path.is_dir() {
+            if options.read_only || !options.create_if_missing {
+                bail!("No store found at {:?}", path);
+            }
+            fs::create_dir_all(&*path).context("Creating directory for log files")?;
+        }
+
+        let gen_list = sorted_gen_list(&path)?;
+        if gen_list.is_empty() && !options.create_if_missing {
+            bail!("No store found at {:?}", path);
+        }
+        if !gen_list.is_empty() && options.error_if_exists {
+            bail!("Store already exists at {:?}", path);
+        }
+        let lock = if options.read_only {
+            DirLock::shared(&path)?
+        } else {
+            Some(DirLock::exclusive(&path)?)
+        };
+        match Self::open_locked(path, options) {
+            Ok(store) => Ok(Self {
+                _lock: lock.map(Arc::new),
+                ..store
+            }),
+            Err(err) => {
+                if let Some(lock) = lock {
+                    lock.abandon();
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Opens the store at `path`, once its lock is held.
+    fn open_locked(path: Arc<PathBuf>, options: Arc<OpenOptions>) -> Result<Self> {
+        let mut manifest = Manifest::load(Arc::clone(&path), Arc::new(Codec::new(&options)))?;
+        let mut codec = Codec::new(&options);
+        if manifest.encrypted() {
+            // a record written without encryption can only have been forged by someone without the key
+            codec.cipher.reject_plaintext();
+        } else if codec.cipher.is_enabled() && !options.migrate_to_encryption {
+            bail!("Store is not encrypted yet, see OpenOptions::migrate_to_encryption");
+        }
+        let codec = Arc::new(codec);
+        if !options.read_only {
+            manifest.remove_orphans()?;
+        }
+        let gen_list = manifest.generations();
+        let mut namespaces = Namespaces::new();
+        // records of legacy generations are read without framing; compaction rewrites them into the current format
+        let mut legacy_generations = BTreeSet::new();
+        let mut wasted_bytes = 0;
+        for &generation in &gen_list {
+            wasted_bytes += load(
+                &path,
+                generation,
+                &mut namespaces,
+                &mut legacy_generations,
+                &options,
+                &codec.cipher,
+            )?;
+        }
+        for (_, _, map) in namespaces.all() {
+            wasted_bytes += map.write().unwrap().purge_expired();
+        }
+
+        let map = namespaces.map(DEFAULT_NAMESPACE)?;
+        let namespaces = Arc::new(RwLock::new(namespaces));
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let reader = KvStoreReader::new(
+            Arc::clone(&path),
+            safe_point,
+            Arc::clone(&options),
+            codec,
+            Arc::new(legacy_generations),
+        );
+        let (writer, syncer) = if options.read_only {
+            (None, Syncer::start(SyncPolicy::Never, None)?)
+        } else {
+            let current_generation = match gen_list.last() {
+                // new records are never appended to a file written in an older format
+                Some(&last) if format_version(&path, last)? == FORMAT_VERSION => last,
+                Some(&last) => last + 1,
+                // Brand new database, so start with current_generation = 1
+                None => 1,
+            };
+            let writer = get_write_handle(
+                &path,
+                current_generation,
+                LogFileType::Blessed,
+                options.write_buffer_size,
+            )
+            .context("Opening file for writing during initialization")?;
+            // also writes the manifest of stores which don't have one yet
+            manifest.add(current_generation)?;
+            let syncer = Syncer::start(options.sync_policy, Some(writer.get_ref().try_clone()?))?;
+            let writer = KvStoreWriter {
+                path: Arc::clone(&path),
+                namespaces: Arc::clone(&namespaces),
+                reader: reader.clone(),
+                syncer: Arc::clone(&syncer),
+                manifest: Arc::new(Mutex::new(manifest)),
+                options,
+                current_generation,
+                writer,
+                wasted_bytes,
+                compaction: None,
+                joining: false,
+                joined: Arc::new(Condvar::new()),
+                compaction_error: None,
+            };
+            (Some(Arc::new(Mutex::new(writer))), syncer)
+        };
+
+        Ok(Self {
+            path,
+            namespace: DEFAULT_NAMESPACE,
+            namespaces,
+            map,
+            reader: Mutex::new(reader),
+            writer,
+            syncer,
+            _lock: None,
+        })
+    }
+
+    /// Returns the writer shared by all handles, failing if the store was opened read-only.
+    fn writer(&self) -> Result<&Mutex<KvStoreWriter>> {
+        match &self.writer {
+            Some(writer) => Ok(writer),
+            None => bail!("Store was opened read-only"),
+        }
+    }
+
+    /// Blocks until the compaction currently running in the background (if any) has finished.
+    ///
+    /// # Errors
+    ///
+    /// It propagates any error which made the compaction fail, or else made the last background
+    /// compaction fail since the previous call: such errors never fail the writes which started them.
+    pub fn wait_for_compaction(&self) -> Result<()> {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        join_compaction(writer, false)?;
+        match writer.lock().unwrap().compaction_error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Compacts the store now, whatever its wasted bytes, and blocks until the compaction has finished.
+    ///
+    /// Compaction rewrites every live record, so this also applies a new `Compression` or encryption key
+    /// to all the data of the store.
+    pub fn compact(&self) -> Result<()> {
+        lock_idle_writer(self.writer()?).start_compaction()?;
+        self.wait_for_compaction()
+    }
+
+    /// Rewrites the log files written in an older on-disk format into the current one, by compacting the store.
+    /// Returns the number of generations which were in an older format (nothing is done if there are none).
+    ///
+    /// Older generations stay readable, so migrating is only needed before they can be read by a version of kvs
+    /// which no longer supports their format. New records are never written into a file of an older format.
+    pub fn migrate(&self) -> Result<usize> {
+        let generations = self
+            .writer()?
+            .lock()
+            .unwrap()
+            .manifest
+            .lock()
+            .unwrap()
+            .generations();
+        let mut outdated = 0;
+        for generation in generations {
+            if format_version(&self.path, generation)? < FORMAT_VERSION {
+                outdated += 1;
+            }
+        }
+        if outdated > 0 {
+            self.compact()?;
+        }
+        Ok(outdated)
+    }
+
+    /// Set a `value` for `key`, both of which can be arbitrary bytes.
+    /// If `key` was already present, the new `value` will override it.
+    pub fn set_bytes(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let seq = self.writer()?.lock().unwrap().set(
+            self.namespace,
+            key.to_vec(),
+            value.to_vec(),
+            None,
+        )?;
+        self.syncer.wait_for(seq)
+    }
+
+    /// Set a `value` for `key` which expires after `ttl`. Once expired, the key is treated as absent,
+    /// and its value is reclaimed by the next compaction.
+    /// If `key` was already present, the new `value` (and expiry) will override it.
+    pub fn set_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let seq = self.writer()?.lock().unwrap().set(
+            self.namespace,
+            key.to_vec(),
+            value.to_vec(),
+            Some(expires_at),
+        )?;
+        self.syncer.wait_for(seq)
+    }
+
+    /// Get Some(value) from the KvStore, searching by the bytes of `key`. If the `key` is not present, None will be returned.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // the index stays read-locked while reading from disk, so compaction can't remove the file underneath us
+        let map = self.map.read().unwrap();
+        match map.get(key)? {
+            None => Ok(None),
+            Some(entry) => self.read_value(key, &entry),
+        }
+    }
+
+    /// Returns a read-only view of the store as it is now. Reads from the snapshot ignore all the writes
+    /// done after it was taken, so that many keys can be read consistently while writes keep going.
+    ///
+    /// While a snapshot is alive, overwritten and removed values stay reachable, and compaction leaves
+    /// the previous log files on disk, so snapshots should not be kept around for longer than needed.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, Result};
+    /// # fn try_main() -> Result<()> {
+    /// # let store = KvStore::open(std::env::current_dir()?)?;
+    /// store.set("key".to_owned(), "old".to_owned())?;
+    /// let snapshot = store.snapshot();
+    /// store.set("key".to_owned(), "new".to_owned())?;
+    /// assert_eq!(snapshot.get("key".to_owned())?, Some("old".to_owned()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        let seq = self.map.write().unwrap().take_snapshot();
+        Snapshot {
+            store: self.clone(),
+            seq,
+        }
+    }
+
+    /// Starts a read-modify-write `Transaction` over any number of keys. See `Transaction`.
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.snapshot())
+    }
+
+    /// Commits the writes of a transaction, unless a key it read was changed since. See `KvStoreWriter::commit_transaction`.
+    pub(crate) fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Result<()> {
+        let seq =
+            self.writer()?
+                .lock()
+                .unwrap()
+                .commit_transaction(self.namespace, reads, writes)?;
+        match seq {
+            Some(seq) => self.syncer.wait_for(seq),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies all the sets and removes of `batch` atomically: they are written to the log as a single record,
+    /// so readers and log replay see either all of them or none.
+    ///
+    /// # Errors
+    ///
+    /// If the batch removes a key which doesn't exist at that point, nothing is written and an error is returned.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let seq = self
+            .writer()?
+            .lock()
+            .unwrap()
+            .write_batch(self.namespace, batch.commands)?;
+        self.syncer.wait_for(seq)
+    }
+
+    /// Returns an iterator over the keys within `range` and their values, in key order.
+    ///
+    /// The iterator is lazy: each step looks up the next key in the index, and reads its value from disk.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn try_main() -> Result<()> {
+    /// # let store = KvStore::open(std::env::current_dir()?)?;
+    /// for entry in store.scan("user:100".."user:200") {
+    ///     let (key, value) = entry?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn scan<K, R>(&self, range: R) -> Scan<'_>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        let to_owned = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        Scan {
+            store: self,
+            lower: to_owned(range.start_bound()),
+            upper: to_owned(range.end_bound()),
+            seq: None,
+        }
+    }
+
+    /// Returns an iterator over the keys starting with `prefix` and their values, in key order.
+    /// See `KvStore::scan`.
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan<'_> {
+        let prefix = prefix.as_ref();
+        // the first key after all keys starting with prefix, e.g. "user:" -> "user;"
+        let mut end = prefix.to_vec();
+        while end.last() == Some(&u8::MAX) {
+            end.pop();
+        }
+        let upper = match end.last_mut() {
+            Some(last) => {
+                *last += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        Scan {
+            store: self,
+            lower: Bound::Included(prefix.to_vec()),
+            upper,
+            seq: None,
+        }
+    }
+
+    /// Reads the value of `key` stored at the location described by `entry`.
+    fn read_value(&self, key: &[u8], entry: &LogEntry) -> Result<Option<Vec<u8>>> {
+        let cmd = self.reader.lock().unwrap().read_command(entry)?;
+        Ok(cmd.value_of(key))
+    }
+
+    /// Removes the bytes `key` from the KvStore. This will throw an error if the `key` does not already exist.
+    pub fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let seq = self
+            .writer()?
+            .lock()
+            .unwrap()
+            .remove(self.namespace, key.to_vec())?;
+        self.syncer.wait_for(seq)
+    }
+
+    /// Atomically replaces the value of `key` by `new`, but only if its current value is `expected`.
+    /// A value of None stands for a missing key, so `expected: None` requires the key to be absent,
+    /// and `new: None` removes the key.
+    ///
+    /// Returns whether the swap happened. The comparison and the write are done under the writer lock,
+    /// so no other write can sneak in between them.
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<bool> {
+        self.swap(key, expected, new, false)
+    }
+
+    /// Like `KvStore::compare_and_swap`, but the new value keeps the time-to-live of the current one, if any.
+    pub(crate) fn compare_and_swap_keeping_ttl(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool> {
+        self.swap(key, expected, Some(new), true)
+    }
+
+    fn swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+        keep_ttl: bool,
+    ) -> Result<bool> {
+        if expected.is_none() && new.is_none() {
+            // nothing would be written, so this boils down to checking the key is absent
+            return Ok(self.get_bytes(key)?.is_none());
+        }
+        let seq = self.writer()?.lock().unwrap().compare_and_swap(
+            self.namespace,
+            key.to_vec(),
+            expected,
+            new.map(<[u8]>::to_vec),
+            keep_ttl,
+        )?;
+        match seq {
+            Some(seq) => {
+                self.syncer.wait_for(seq)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Sets `key` to `value`, but only if `key` doesn't exist yet. Returns whether the value was set.
+    pub fn set_if_absent(&self, key: &[u8], value: &[u8]) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Removes `key`, but only if its current value is `expected`. Returns whether the key was removed.
+    pub fn remove_if_equals(&self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// Makes `key` expire after `ttl`, keeping its current value. Returns whether the key exists.
+    /// Like `compare_and_swap`, the value is read and written again under the writer lock.
+    pub fn expire(&self, key: &[u8], ttl: Duration) -> Result<bool> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        let seq =
+            self.writer()?
+                .lock()
+                .unwrap()
+                .expire(self.namespace, key.to_vec(), expires_at)?;
+        match seq {
+            Some(seq) => {
+                self.syncer.wait_for(seq)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns a handle to the namespace `name`, creating it if it doesn't exist yet.
+    ///
+    /// Namespaces are logically separate sets of keys, which share the log files (and compaction) of the store.
+    /// The handle returned by `KvStore::open` is a handle to the namespace named "default", which always exists.
+    /// All the methods of a handle (including `snapshot`, `transaction` and `stats`) only see the keys of its namespace.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, Result};
+    /// # fn try_main() -> Result<()> {
+    /// # let store = KvStore::open(std::env::current_dir()?)?;
+    /// let users = store.namespace("users")?;
+    /// users.set("alice".to_owned(), "admin".to_owned())?;
+    /// assert_eq!(store.get("alice".to_owned())?, None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Creating a namespace fails if the store was opened read-only.
+    pub fn namespace(&self, name: &str) -> Result<KvStore> {
+        let existing = self.namespaces.read().unwrap().id_of(name);
+        let namespace = match existing {
+            Some(namespace) => namespace,
+            None => {
+                let (namespace, seq) = self.writer()?.lock().unwrap().create_namespace(name)?;
+                if let Some(seq) = seq {
+                    self.syncer.wait_for(seq)?;
+                }
+                namespace
+            }
+        };
+        let map = self.namespaces.read().unwrap().map(namespace)?;
+        Ok(Self {
+            namespace,
+            map,
+            ..self.clone()
+        })
+    }
+
+    /// Returns the names of all the namespaces of the store, in the order they were created.
+    pub fn namespaces(&self) -> Vec<String> {
+        self.namespaces.read().unwrap().names()
+    }
+
+    /// Drops the namespace `name` with all of its keys. Handles to the namespace see it empty,
+    /// and fail to write to it.
+    ///
+    /// # Errors
+    ///
+    /// This fails if the namespace doesn't exist, or if it is the default namespace.
+    pub fn drop_namespace(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE_NAME {
+            bail!("The default namespace can't be dropped");
+        }
+        let namespace = match self.namespaces.read().unwrap().id_of(name) {
+            Some(namespace) => namespace,
+            None => bail!("Namespace not found"),
+        };
+        let seq = self.writer()?.lock().unwrap().drop_namespace(namespace)?;
+        self.syncer.wait_for(seq)
+    }
+
+    /// Returns statistics about the store.
+    pub fn stats(&self) -> Stats {
+        let (wasted_bytes, compacting, compression_ratio) = match &self.writer {
+            Some(writer) => {
+                let writer = writer.lock().unwrap();
+                let compacting = writer.joining
+                    || writer
+                        .compaction
+                        .as_ref()
+                        .is_some_and(|compaction| !compaction.handle.is_finished());
+                (
+                    writer.wasted_bytes,
+                    compacting,
+                    writer.reader.codec.compressor.ratio(),
+                )
+            }
+            None => (0, false, 1.0),
+        };
+        Stats {
+            keys: self.map.read().unwrap().len(),
+            wasted_bytes,
+            compacting,
+            sync_policy: self.syncer.policy(),
+            syncs: self.syncer.syncs(),
+            compression_ratio,
+        }
+    }
+
+    /// Stops the compaction currently running in the background (if any), and waits for it to exit.
+    ///
+    /// A cancelled compaction leaves the previous log files untouched, so they will simply be
+    /// picked up again by the next compaction.
+    pub fn cancel_compaction(&self) -> Result<()> {
+        let writer = match &self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        join_compaction(writer, true)
+    }
+}
+
+/// Locks the writer, once no other handle is waiting for a compaction to exit.
+fn lock_idle_writer(writer: &Mutex<KvStoreWriter>) -> MutexGuard<'_, KvStoreWriter> {
+    let mut guard = writer.lock().unwrap();
+    while guard.joining {
+        let joined = Arc::clone(&guard.joined);
+        guard = joined.wait(guard).unwrap();
+    }
+    guard
+}
+
+/// Waits for the compaction running in the background (if any) to exit, cancelling it first if `cancel`.
+/// The writer isn't held meanwhile, so that writes go on, but no other compaction starts until it has exited.
+fn join_compaction(writer: &Mutex<KvStoreWriter>, cancel: bool) -> Result<()> {
+    let compaction = {
+        let mut guard = lock_idle_writer(writer);
+        match guard.compaction.take() {
+            Some(compaction) => {
+                guard.joining = true;
+                compaction
+            }
+            None => return Ok(()),
+        }
+    };
+    let wasted_bytes = compaction.wasted_bytes;
+    let result = if cancel {
+        compaction.cancel()
+    } else {
+        compaction.join()
+    };
+    let mut guard = writer.lock().unwrap();
+    guard.joining = false;
+    guard.joined.notify_all();
+    guard.compaction_exited(wasted_bytes, result)
+}
+
+impl Clone for KvStore {
+    /// Creates a new handle to the same store. The new handle opens its own reader handles lazily.
+    fn clone(&self) -> Self {
+        Self {
+            path: Arc::clone(&self.path),
+            namespace: self.namespace,
+            namespaces: Arc::clone(&self.namespaces),
+            map: Arc::clone(&self.map),
+            reader: Mutex::new(self.reader.lock().unwrap().clone()),
+            writer: self.writer.clone(),
+            syncer: Arc::clone(&self.syncer),
+            _lock: self._lock.clone(),
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    /// Set a `value` for `key`. If `key` was already present, the new `value` will override it.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// Get Some(value) from the KvStore, searching by `key`. If the `key` is not present, None will be returned.
+    ///
+    /// # Errors
+    ///
+    /// On top of I/O errors, this fails if the value was stored as bytes which are not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(
+                String::from_utf8(value).context("Value is not valid UTF-8")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes `key` from the KvStore. This will throw an error if the `key` does not already exist.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+}
+
+/// A lazy iterator over a range of keys and their values, returned by `KvStore::scan` and `KvStore::scan_prefix`.
+///
+/// Keys written while iterating are seen if they come after the current position of the iterator
+/// (unless the iterator comes from a `Snapshot`).
+#[derive(Debug)]
+pub struct Scan<'a> {
+    store: &'a KvStore,
+    // bound of the keys which remain to be returned, moved past every key returned
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    // sequence number of the snapshot the iterator reads from, if any
+    seq: Option<u64>,
+}
+
+impl<'a> Scan<'a> {
+    /// Makes the iterator read keys as seen by the snapshot pinned to `seq`.
+    fn pinned_to(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// Turns the iterator into one over the keys alone, which only walks the index and never reads from disk.
+    pub(crate) fn keys(self) -> Keys<'a> {
+        Keys { scan: self }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let map = self.store.map.read().unwrap();
+            let (key, entry) = map.first_in_range(
+                as_slice_bound(&self.lower),
+                as_slice_bound(&self.upper),
+                self.seq,
+            )?;
+            self.lower = Bound::Excluded(key.clone());
+            match self.store.read_value(&key, &entry) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// An iterator over the keys of a range, in key order. Returned by `Scan::keys`.
+pub(crate) struct Keys<'a> {
+    scan: Scan<'a>,
+}
+
+impl Iterator for Keys<'_> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let scan = &mut self.scan;
+        let (key, _) = scan.store.map.read().unwrap().first_in_range(
+            as_slice_bound(&scan.lower),
+            as_slice_bound(&scan.upper),
+            scan.seq,
+        )?;
+        scan.lower = Bound::Excluded(key.clone());
+        Some(key)
+    }
+}
+
+/// A read-only view of a `KvStore`, pinned to the log sequence number at which it was taken.
+/// Returned by `KvStore::snapshot`.
+#[derive(Debug)]
+pub struct Snapshot {
+    store: KvStore,
+    seq: u64,
+}
+
+impl Snapshot {
+    /// The log sequence number the snapshot is pinned to. It counts the writes applied since the store was opened.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Get Some(value) as of the snapshot, searching by the bytes of `key`. If the `key` was not present, None will be returned.
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // the index stays read-locked while reading from disk, like `KvStore::get_bytes`
+        let map = self.store.map.read().unwrap();
+        match map.get_at(key, self.seq) {
+            None => Ok(None),
+            Some(entry) => self.store.read_value(key, &entry),
+        }
+    }
+
+    /// The store the snapshot was taken from.
+    pub(crate) fn store(&self) -> &KvStore {
+        &self.store
+    }
+
+    /// Like `Snapshot::get_bytes`, but also returns the sequence number of the write which set the value.
+    pub(crate) fn get_versioned(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Option<u64>)> {
+        let map = self.store.map.read().unwrap();
+        match map.get_at(key, self.seq) {
+            None => Ok((None, None)),
+            Some(entry) => Ok((self.store.read_value(key, &entry)?, Some(entry.seq))),
+        }
+    }
+
+    /// Get Some(value) as of the snapshot, searching by `key`. If the `key` was not present, None will be returned.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(
+                String::from_utf8(value).context("Value is not valid UTF-8")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns an iterator over the keys within `range` and their values as of the snapshot, in key order.
+    /// See `KvStore::scan`.
+    pub fn scan<K, R>(&self, range: R) -> Scan<'_>
+    where
+        K: AsRef<[u8]>,
+        R: RangeBounds<K>,
+    {
+        self.store.scan(range).pinned_to(self.seq)
+    }
+
+    /// Returns an iterator over the keys starting with `prefix` and their values as of the snapshot, in key order.
+    /// See `KvStore::scan`.
+    pub fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Scan<'_> {
+        self.store.scan_prefix(prefix).pinned_to(self.seq)
+    }
+}
+
+impl Drop for Snapshot {
+    /// Releases the versions only this snapshot could see, and removes the log files compaction left behind for it.
+    fn drop(&mut self) {
+        let obsolete_generations = self.store.map.write().unwrap().release_snapshot(self.seq);
+        if obsolete_generations.is_empty() {
+            return;
+        }
+        // snapshots of other namespaces may still read from the same generations
+        let namespaces = self.store.namespaces.read().unwrap().all();
+        for (_, _, map) in namespaces {
+            if map.write().unwrap().defer_removal(&obsolete_generations) {
+                return;
+            }
+        }
+        for generation in obsolete_generations {
+            let _ = remove_generation(&self.store.path, generation);
+        }
+    }
+}
+
+fn as_slice_bound(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// load will read a generation's log file from disk, modifying the in-memory maps with the proper file offsets.
+/// Returns estimate of wasted bytes detected in this generation.
+///
+/// A record torn by a crash at the end of the file is truncated away (unless the store is read-only),
+/// while a corrupted record anywhere else fails the load. Generations written in format version 0 are added
+/// to `legacy_generations`.
+fn load(
+    path: &Path,
+    generation: u64,
+    namespaces: &mut Namespaces,
+    legacy_generations: &mut BTreeSet<u64>,
+    options: &OpenOptions,
+    cipher: &Cipher,
+) -> Result<usize> {
+    if let Some((created_namespaces, hints)) = read_hints(path, generation, options, cipher)? {
+        for (id, name) in created_namespaces.namespaces {
+            namespaces.create(id, name);
+        }
+        let mut wasted_bytes = 0;
+        for hint in hints {
+            wasted_bytes += namespaces.map(hint.namespace)?.write().unwrap().set(
+                &hint.key,
+                hint.generation,
+                hint.file_pos,
+                hint.estimated_bytes,
+                hint.expires_at,
+            )?;
+        }
+        return Ok(wasted_bytes);
+    }
+
+    let mut reader = get_read_handle(
+        path,
+        generation,
+        LogFileType::Blessed,
+        options.read_buffer_size,
+    )
+    .context("Opening file for reading during load")?;
+    let file_len = reader.get_ref().metadata()?.len();
+    let mut current_pos = match read_file_header(&mut reader, file_len)
+        .with_context(|| format!("Replaying generation {}", generation))?
+    {
+        // legacy records are never encrypted
+        Some((LEGACY_FORMAT_VERSION, _)) if cipher.rejects_plaintext() => {
+            bail!(
+                "Generation {} is not encrypted, but the store is",
+                generation
+            )
+        }
+        Some((LEGACY_FORMAT_VERSION, _)) => {
+            legacy_generations.insert(generation);
+            return load_legacy(reader, generation, namespaces, file_len);
+        }
+        Some((_, first_record_pos)) => first_record_pos,
+        None => {
+            if file_len > 0 && !options.read_only {
+                truncate_log(path, generation, 0)?;
+            }
+            return Ok(0);
+        }
+    };
+    reader.seek(SeekFrom::Start(current_pos))?;
+    let mut wasted_bytes = 0;
+    // writes of the transaction being replayed, waiting for its commit marker (with their position)
+    let mut transaction_writes = Vec::new();
+    loop {
+        let (cmd, len) = match read_record(&mut reader, current_pos, file_len, cipher)
+            .with_context(|| format!("Replaying generation {}", generation))?
+        {
+            Record::Valid(cmd, len) => (cmd, len),
+            Record::Eof => break,
+            Record::Torn => {
+                if !options.read_only {
+                    truncate_log(path, generation, current_pos)?;
+                }
+                break;
+            }
+        };
+        match cmd {
+            // compaction picks the namespaces to create in its compacted log once new writes already go to
+            // the generation after its target, so a namespace dropped in the meantime is never created again:
+            // its last writes and its drop are skipped
+            Command::DropNamespace { id } if !namespaces.contains(id) => {}
+            Command::TransactionWrite { .. } | Command::Namespaced { .. }
+                if !namespaces.contains(cmd.namespace()) => {}
+            Command::TransactionWrite { .. } => transaction_writes.push((cmd, current_pos)),
+            Command::TransactionCommit { txn } => {
+                // a transaction's records are contiguous, so any other pending write belongs to
+                // a transaction which never committed
+                let committed: Vec<_> = transaction_writes
+                    .drain(..)
+                    .filter(|(cmd, _)| {
+                        matches!(cmd, Command::TransactionWrite { txn: write_txn, .. } if *write_txn == txn)
+                    })
+                    .collect();
+                wasted_bytes += apply_transaction(namespaces, &committed, generation)?;
+            }
+            Command::CreateNamespace { id, name } => namespaces.create(id, name),
+            Command::DropNamespace { id } => wasted_bytes += namespaces.remove(id)?,
+            _ => wasted_bytes += apply(namespaces, &cmd, generation, current_pos)?,
+        }
+        current_pos += len;
+    }
+    Ok(wasted_bytes)
+}
+
+/// Replays a generation written before records were framed, the way the versions of kvs which wrote it did:
+/// commands are read until one can't be decoded, which is where a crash tore the last write. The file is left
+/// untouched, since new records are never appended to it.
+fn load_legacy(
+    mut reader: BufReader<fs::File>,
+    generation: u64,
+    namespaces: &mut Namespaces,
+    file_len: u64,
+) -> Result<usize> {
+    let mut current_pos = reader.seek(SeekFrom::Start(0))?;
+    let mut wasted_bytes = 0;
+    while let Ok(cmd) = Command::from_legacy_reader(&mut reader, file_len - current_pos) {
+        wasted_bytes += apply(namespaces, &cmd, generation, current_pos)?;
+        current_pos = reader.stream_position()?;
+    }
+    Ok(wasted_bytes)
+}
+
+/// Applies a record written at `file_pos` of `generation` to the in-memory map of its namespace,
+/// under a new sequence number. All the commands of a batch point at the batch's record.
+/// Returns estimate of wasted bytes detected.
+fn apply(namespaces: &Namespaces, cmd: &Command, generation: u64, file_pos: u64) -> Result<usize> {
+    let map = namespaces.map(cmd.namespace())?;
+    let mut map = map.write().unwrap();
+    map.next_seq();
+    apply_command(&mut map, cmd, generation, file_pos)
+}
+
+/// Applies the writes of a committed transaction (with their position in `generation`) under a single sequence number.
+/// Returns estimate of wasted bytes detected.
+fn apply_transaction(
+    namespaces: &Namespaces,
+    writes: &[(Command, u64)],
+    generation: u64,
+) -> Result<usize> {
+    let namespace = match writes.first() {
+        Some((cmd, _)) => cmd.namespace(),
+        None => return Ok(0),
+    };
+    let map = namespaces.map(namespace)?;
+    let mut map = map.write().unwrap();
+    map.next_seq();
+    let mut wasted_bytes = 0;
+    for (cmd, file_pos) in writes {
+        wasted_bytes += apply_command(&mut map, cmd, generation, *file_pos)?;
+    }
+    Ok(wasted_bytes)
+}
+
+fn apply_command(
+    map: &mut InternalMap,
+    cmd: &Command,
+    generation: u64,
+    file_pos: u64,
+) -> Result<usize> {
+    match cmd {
+        Command::Set { key, value } => {
+            let estimated_bytes = key.len() + value.len();
+            map.set(key, generation, file_pos, estimated_bytes, None)
+        }
+        Command::SetWithExpiry {
+            key,
+            value,
+            expires_at,
+        } => {
+            let estimated_bytes = key.len() + value.len();
+            map.set(
+                key,
+                generation,
+                file_pos,
+                estimated_bytes,
+                Some(*expires_at),
+            )
+        }
+        // compaction picks the keys to copy once new writes already go to the generation after its target,
+        // so the removal of a key may follow a compacted log which doesn't have it anymore
+        Command::Remove { key } => Ok(map.remove(key).unwrap_or(0)),
+        Command::Batch { commands } => {
+            let mut wasted_bytes = 0;
+            for cmd in commands {
+                wasted_bytes += apply_command(map, cmd, generation, file_pos)?;
+            }
+            Ok(wasted_bytes)
+        }
+        Command::TransactionWrite { command, .. } | Command::Namespaced { command, .. } => {
+            apply_command(map, command, generation, file_pos)
+        }
+        Command::TransactionCommit { .. } => Ok(0),
+        Command::CreateNamespace { .. } | Command::DropNamespace { .. } => {
+            bail!("Namespaces are not applied to a map")
+        }
+    }
+}
+
+/// Reads the hint file of a generation, if compaction wrote one.
+/// Returns None when there is no usable hint file, in which case the generation's log must be replayed instead.
+fn read_hints(
+    path: &Path,
+    generation: u64,
+    options: &OpenOptions,
+    cipher: &Cipher,
+) -> Result<Option<(NamespacesHint, Vec<Hint>)>> {
+    let mut reader = match get_read_handle(
+        path,
+        generation,
+        LogFileType::Hint,
+        options.read_buffer_size,
+    ) {
+        Ok(reader) => reader,
+        Err(_) => return Ok(None),
+    };
+    let file_len = reader.get_ref().metadata()?.len();
+    // hint files are written atomically, so a damaged one (or one in an older format) is not to be trusted at all
+    let first_record_pos = match read_file_header(&mut reader, file_len) {
+        Ok(Some((FORMAT_VERSION, first_record_pos))) => first_record_pos,
+        _ => return Ok(None),
+    };
+    let (namespaces, mut current_pos) =
+        match read_record(&mut reader, first_record_pos, file_len, cipher) {
+            Ok(Record::Valid(namespaces, len)) => (namespaces, first_record_pos + len),
+            _ => return Ok(None),
+        };
+    let mut hints = Vec::new();
+    loop {
+        match read_record::<Hint, _>(&mut reader, current_pos, file_len, cipher) {
+            Ok(Record::Valid(hint, len)) if hint.generation == generation => {
+                hints.push(hint);
+                current_pos += len;
+            }
+            Ok(Record::Eof) => return Ok(Some((namespaces, hints))),
+            _ => return Ok(None),
+        }
+    }
+}
+
+/// KvStoreReader owns the reader handles of a single `KvStore` handle.
+/// Handles are opened lazily, the first time a generation is read from.
+#[derive(Debug)]
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // generations below this point have been compacted away, so their handles can be closed
+    safe_point: Arc<AtomicU64>,
+    options: Arc<OpenOptions>,
+    // decodes the records read (shared by all readers, and used by the writer to encode records)
+    codec: Arc<Codec>,
+    // generations of format version 0, whose records are not framed
+    legacy_generations: Arc<BTreeSet<u64>>,
+    readers: BTreeMap<u64, BufReader<fs::File>>,
+}
+
+impl KvStoreReader {
+    fn new(
+        path: Arc<PathBuf>,
+        safe_point: Arc<AtomicU64>,
+        options: Arc<OpenOptions>,
+        codec: Arc<Codec>,
+        legacy_generations: Arc<BTreeSet<u64>>,
+    ) -> Self {
+        Self {
+            path,
+            safe_point,
+            options,
+            codec,
+            legacy_generations,
+            readers: BTreeMap::new(),
+        }
+    }
+
+    /// Read the command stored at the location described by `entry`.
+    fn read_command(&mut self, entry: &LogEntry) -> Result<Command> {
+        self.close_stale_handles();
+        let reader = match self.readers.entry(entry.generation) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            btree_map::Entry::Vacant(vacant) => vacant.insert(get_read_handle(
+                &self.path,
+                entry.generation,
+                LogFileType::Blessed,
+                self.options.read_buffer_size,
+            )?),
+        };
+        reader.seek(SeekFrom::Start(entry.file_pos))?;
+        if self.legacy_generations.contains(&entry.generation) {
+            // the record was already decoded when the generation was loaded
+            return Command::from_legacy_reader(reader, u64::MAX);
+        }
+        Command::from_reader(reader, &self.codec.cipher)
+    }
+
+    /// Close handles to generations which were removed by compaction.
+    fn close_stale_handles(&mut self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        if self
+            .readers
+            .keys()
+            .next()
+            .is_some_and(|&gen| gen < safe_point)
+        {
+            self.readers = self.readers.split_off(&safe_point);
+        }
+    }
+}
+
+impl Clone for KvStoreReader {
+    /// Cloned readers share the safe point, but never file handles.
+    fn clone(&self) -> Self {
+        Self::new(
+            Arc::clone(&self.path),
+            Arc::clone(&self.safe_point),
+            Arc::clone(&self.options),
+            Arc::clone(&self.codec),
+            Arc::clone(&self.legacy_generations),
+        )
+    }
+}
+
+/// KvStoreWriter is the single writer of a store, shared by all `KvStore` handles behind a mutex.
+#[derive(Debug)]
+struct KvStoreWriter {
+    path: Arc<PathBuf>,
+    namespaces: Arc<RwLock<Namespaces>>,
+    // reader used to check conditional writes, and to copy values during compaction
+    reader: KvStoreReader,
+    syncer: Arc<Syncer>,
+    // live generations (shared with compaction)
+    manifest: Arc<Mutex<Manifest>>,
+    options: Arc<OpenOptions>,
+    // current generation
+    current_generation: u64,
+    // current write handle (to current generation)
+    writer: BufWriter<fs::File>,
+    // keep track of wasted bytes (eligible for compaction)
+    wasted_bytes: usize,
+    // compaction running in the background, if any
+    compaction: Option<Compaction>,
+    // whether a handle is waiting for the compaction to exit, without holding the writer
+    joining: bool,
+    // notified once that handle is done
+    joined: Arc<Condvar>,
+    // error of the last background compaction which failed, kept for `wait_for_compaction`
+    compaction_error: Option<anyhow::Error>,
+}
+
+impl KvStoreWriter {
+    /// Returns the map of `namespace`, failing if it was dropped.
+    fn map(&self, namespace: u32) -> Result<Arc<RwLock<InternalMap>>> {
+        self.namespaces.read().unwrap().map(namespace)
+    }
+
+    /// Writes a `Set` command (or `SetWithExpiry`, if the key expires) to the log, returning the sequence number
+    /// of the write.
+    fn set(
+        &mut self,
+        namespace: u32,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        self.map(namespace)?;
+        let current_pos = self.writer.seek(SeekFrom::End(0))?;
+        let cmd = Command::in_namespace(namespace, Command::set(key, value, expires_at));
+        cmd.to_writer(&mut self.writer, &self.reader.codec)?;
+        let seq = self.flush()?;
+        // internal book-keeping performed after successful disk write
+        self.wasted_bytes += apply(
+            &self.namespaces.read().unwrap(),
+            &cmd,
+            self.current_generation,
+            current_pos,
+        )?;
+        self.maybe_run_compaction()?;
+        Ok(seq)
+    }
+
+    /// Writes a `Remove` command to the log, returning the sequence number of the write.
+    fn remove(&mut self, namespace: u32, key: Vec<u8>) -> Result<u64> {
+        // check before writing, so that we never log a removal of a key which doesn't exist
+        if self.map(namespace)?.read().unwrap().get(&key)?.is_none() {
+            bail!("Key not found");
+        }
+        let current_pos = self.writer.seek(SeekFrom::End(0))?;
+        let cmd = Command::in_namespace(namespace, Command::Remove { key });
+        cmd.to_writer(&mut self.writer, &self.reader.codec)?;
+        let seq = self.flush()?;
+        // internal book-keeping performed after successful disk write
+        self.wasted_bytes += apply(
+            &self.namespaces.read().unwrap(),
+            &cmd,
+            self.current_generation,
+            current_pos,
+        )?;
+        self.maybe_run_compaction()?;
+        Ok(seq)
+    }
+
+    /// Writes `new` (or removes `key` if `new` is None), but only if the current value of `key` is `expected`.
+    /// With `keep_ttl`, `new` expires when the current value would have.
+    /// Returns the sequence number of the write, or None if the current value didn't match.
+    fn compare_and_swap(
+        &mut self,
+        namespace: u32,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+        keep_ttl: bool,
+    ) -> Result<Option<u64>> {
+        let current = self.current_value(namespace, &key)?;
+        if current.as_deref() != expected {
+            return Ok(None);
+        }
+        let expires_at = match self.map(namespace)?.read().unwrap().get(&key)? {
+            Some(entry) if keep_ttl => entry.expires_at,
+            _ => None,
+        };
+        match new {
+            Some(value) => self.set(namespace, key, value, expires_at).map(Some),
+            None => self.remove(namespace, key).map(Some),
+        }
+    }
+
+    /// Sets the current value of `key` again, to expire at `expires_at`. Returns the sequence number of the write,
+    /// or None if the key doesn't exist.
+    fn expire(&mut self, namespace: u32, key: Vec<u8>, expires_at: u64) -> Result<Option<u64>> {
+        match self.current_value(namespace, &key)? {
+            Some(value) => self.set(namespace, key, value, Some(expires_at)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn current_value(&mut self, namespace: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // the index stays read-locked while reading, so compaction can't remove the file underneath us
+        let map = self.map(namespace)?;
+        let map = map.read().unwrap();
+        match map.get(key)? {
+            Some(entry) => Ok(self.reader.read_command(&entry)?.value_of(key)),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes the commands of a batch to the log as a single `Batch` record, returning the sequence number of the write.
+    fn write_batch(&mut self, namespace: u32, commands: Vec<Command>) -> Result<u64> {
+        // check before writing, so that we never log a batch which can't be replayed: every removed key
+        // must exist, either in the index or because of an earlier command of the same batch
+        {
+            let map = self.map(namespace)?;
+            let map = map.read().unwrap();
+            let mut exists_after_batch = HashMap::new();
+            for cmd in &commands {
+                match cmd {
+                    Command::Set { key, .. } | Command::SetWithExpiry { key, .. } => {
+                        exists_after_batch.insert(key.as_slice(), true);
+                    }
+                    Command::Remove { key } => {
+                        let exists = match exists_after_batch.get(key.as_slice()) {
+                            Some(&exists) => exists,
+                            None => map.get(key)?.is_some(),
+                        };
+                        if !exists {
+                            bail!("Key not found");
+                        }
+                        exists_after_batch.insert(key.as_slice(), false);
+                    }
+                    _ => bail!("Batches can only hold sets and removes"),
+                }
+            }
+        }
+        let current_pos = self.writer.seek(SeekFrom::End(0))?;
+        let cmd = Command::in_namespace(namespace, Command::Batch { commands });
+        cmd.to_writer(&mut self.writer, &self.reader.codec)?;
+        let seq = self.flush()?;
+        // internal book-keeping performed after successful disk write
+        self.wasted_bytes += apply(
+            &self.namespaces.read().unwrap(),
+            &cmd,
+            self.current_generation,
+            current_pos,
+        )?;
+        self.maybe_run_compaction()?;
+        Ok(seq)
+    }
+
+    /// Commits a transaction: checks that none of the keys it read were changed since (`reads` holds the sequence
+    /// number of the entry each key had, if any), then writes each of `writes` as a `TransactionWrite`, followed by
+    /// the transaction's commit marker. The transaction id is the position of its first record.
+    ///
+    /// Returns the sequence number of the write, or None if there was nothing to write.
+    fn commit_transaction(
+        &mut self,
+        namespace: u32,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> Result<Option<u64>> {
+        let commands: Vec<_> = {
+            let map = self.map(namespace)?;
+            let map = map.read().unwrap();
+            for (key, seen_seq) in &reads {
+                if map.get(key)?.map(|entry| entry.seq) != *seen_seq {
+                    bail!(
+                        "Transaction conflict: {:?} was changed by another writer",
+                        String::from_utf8_lossy(key)
+                    );
+                }
+            }
+            let mut commands = Vec::with_capacity(writes.len());
+            for (key, value) in writes {
+                match value {
+                    Some(value) => commands.push(Command::Set { key, value }),
+                    // a key both set and removed by the transaction may not exist at all
+                    None if map.get(&key)?.is_some() => commands.push(Command::Remove { key }),
+                    None => (),
+                }
+            }
+            commands
+        };
+        if commands.is_empty() {
+            return Ok(None);
+        }
+
+        let txn = self.writer.seek(SeekFrom::End(0))?;
+        let mut current_pos = txn;
+        let mut records = Vec::with_capacity(commands.len());
+        for command in commands {
+            let record = Command::TransactionWrite {
+                txn,
+                command: Box::new(Command::in_namespace(namespace, command)),
+            };
+            let len = record.to_writer(&mut self.writer, &self.reader.codec)?;
+            records.push((record, current_pos));
+            current_pos += len;
+        }
+        Command::TransactionCommit { txn }.to_writer(&mut self.writer, &self.reader.codec)?;
+        let seq = self.flush()?;
+        // internal book-keeping performed after successful disk write
+        self.wasted_bytes += apply_transaction(
+            &self.namespaces.read().unwrap(),
+            &records,
+            self.current_generation,
+        )?;
+        self.maybe_run_compaction()?;
+        Ok(Some(seq))
+    }
+
+    /// Writes a `CreateNamespace` command to the log, unless the namespace `name` already exists.
+    /// Returns the id of the namespace, and the sequence number of the write if there was one.
+    fn create_namespace(&mut self, name: &str) -> Result<(u32, Option<u64>)> {
+        let (existing, id) = {
+            let namespaces = self.namespaces.read().unwrap();
+            (namespaces.id_of(name), namespaces.next_id())
+        };
+        if let Some(existing) = existing {
+            return Ok((existing, None));
+        }
+        let cmd = Command::CreateNamespace {
+            id,
+            name: name.to_owned(),
+        };
+        cmd.to_writer(&mut self.writer, &self.reader.codec)?;
+        let seq = self.flush()?;
+        // internal book-keeping performed after successful disk write
+        self.namespaces.write().unwrap().create(id, name.to_owned());
+        Ok((id, Some(seq)))
+    }
+
+    /// Writes a `DropNamespace` command to the log, returning the sequence number of the write.
+    fn drop_namespace(&mut self, namespace: u32) -> Result<u64> {
+        self.map(namespace)?;
+        Command::DropNamespace { id: namespace }.to_writer(&mut self.writer, &self.reader.codec)?;
+        let seq = self.flush()?;
+        // internal book-keeping performed after successful disk write
+        self.wasted_bytes += self.namespaces.write().unwrap().remove(namespace)?;
+        self.maybe_run_compaction()?;
+        Ok(seq)
+    }
+
+    /// Flushes the current log file to the OS, and hands the write over to the Syncer.
+    /// Returns the sequence number of the write, to wait on with `Syncer::wait_for`.
+    fn flush(&mut self) -> Result<u64> {
+        self.writer.flush()?;
+        self.syncer.record_write()
+    }
+
+    /// Checks if compaction is desired, and if so start a background compaction now.
+    fn maybe_run_compaction(&mut self) -> Result<()> {
+        // expired keys are only dropped from the index here, their values are reclaimed by compaction
+        for (_, _, map) in self.namespaces.read().unwrap().all() {
+            self.wasted_bytes += map.write().unwrap().purge_expired();
+        }
+        if self.joining {
+            // a handle is waiting for the last compaction to exit, and only one compaction runs at a time
+            return Ok(());
+        }
+        if let Some(compaction) = self.compaction.take() {
+            if !compaction.handle.is_finished() {
+                // only one compaction at a time
+                self.compaction = Some(compaction);
+                return Ok(());
+            }
+            // the write which finds out about the failure succeeded, so the error waits for `wait_for_compaction`
+            let wasted_bytes = compaction.wasted_bytes;
+            let result = compaction.join();
+            if let Err(err) = self.compaction_exited(wasted_bytes, result) {
+                self.compaction_error = Some(err);
+            }
+        }
+        if self.wasted_bytes < self.options.compaction_threshold {
+            return Ok(());
+        }
+        self.run_compaction()
+    }
+
+    /// Starts a compaction now, whatever the wasted bytes, once the compaction running (if any) has finished.
+    fn start_compaction(&mut self) -> Result<()> {
+        if let Some(compaction) = self.compaction.take() {
+            let wasted_bytes = compaction.wasted_bytes;
+            let result = compaction.join();
+            self.compaction_exited(wasted_bytes, result)?;
+        }
+        self.run_compaction()
+    }
+
+    /// Accounts for a compaction which has exited, returning its error if it failed. Unless it completed,
+    /// the `wasted_bytes` it was to reclaim are wasted again, so the next compaction picks them up.
+    fn compaction_exited(&mut self, wasted_bytes: usize, result: Result<bool>) -> Result<()> {
+        if !matches!(result, Ok(true)) {
+            self.wasted_bytes += wasted_bytes;
+        }
+        result.map(|_| ())
+    }
+
+    /// Starts a compaction of all the previous generations in the background.
+    fn run_compaction(&mut self) -> Result<()> {
+        // Step 1) Create two new log files, one for compaction and one for new writes.
+        // This is the only step done while holding the writer, all the others happen in the background.
+        let gen_list = self.manifest.lock().unwrap().generations();
+        let compaction_target_generation = self.current_generation + 1;
+        let new_writes_generation = self.current_generation + 2;
+        let compaction_writer = get_write_handle(
+            &self.path,
+            compaction_target_generation,
+            LogFileType::Temporary,
+            self.options.write_buffer_size,
+        )?;
+        let new_writes_writer = get_write_handle(
+            &self.path,
+            new_writes_generation,
+            LogFileType::Blessed,
+            self.options.write_buffer_size,
+        )?;
+        // the new generation must be live before anything is written to it
+        self.manifest.lock().unwrap().add(new_writes_generation)?;
+        self.writer.flush()?;
+        self.writer = new_writes_writer;
+        self.syncer.rotate(self.writer.get_ref().try_clone()?)?;
+        self.current_generation = new_writes_generation;
+        let wasted_bytes = self.wasted_bytes;
+        self.wasted_bytes = 0;
+
+        let compactor = Compactor {
+            path: Arc::clone(&self.path),
+            namespaces: Arc::clone(&self.namespaces),
+            reader: self.reader.clone(),
+            manifest: Arc::clone(&self.manifest),
+            writer: compaction_writer,
+            target_generation: compaction_target_generation,
+            gen_list,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        let cancelled = Arc::clone(&compactor.cancelled);
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || compactor.run())
+            .context("Spawning compaction thread")?;
+        self.compaction = Some(Compaction {
+            handle,
+            cancelled,
+            wasted_bytes,
+        });
+        Ok(())
+    }
+}
+
+impl Drop for KvStoreWriter {
+    /// The last handle of the store waits for a running compaction, so the directory is left in a clean state,
+    /// and syncs any write which is still pending.
+    fn drop(&mut self) {
+        if let Some(compaction) = self.compaction.take() {
+            let _ = compaction.join();
+        }
+        if self.syncer.policy() != SyncPolicy::Never {
+            let _ = self.syncer.sync_all();
+        }
+    }
+}
+
+/// Compaction is a handle to a compaction running in the background.
+#[derive(Debug)]
+struct Compaction {
+    // whether the compaction completed, rather than being cancelled
+    handle: JoinHandle<Result<bool>>,
+    cancelled: Arc<AtomicBool>,
+    // wasted bytes of the previous generations, which the compaction reclaims
+    wasted_bytes: usize,
+}
+
+impl Compaction {
+    fn join(self) -> Result<bool> {
+        match self.handle.join() {
+            Ok(result) => result.context("Running compaction"),
+            Err(_) => bail!("Compaction thread panicked"),
+        }
+    }
+
+    fn cancel(self) -> Result<bool> {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.join()
+    }
+}
+
+/// Compactor rewrites the latest values of all keys in `gen_list` into `target_generation`.
+/// It runs on its own thread, while new writes keep going to the generation after `target_generation`.
+struct Compactor {
+    path: Arc<PathBuf>,
+    namespaces: Arc<RwLock<Namespaces>>,
+    reader: KvStoreReader,
+    manifest: Arc<Mutex<Manifest>>,
+    // write handle to the (temporary) compaction target
+    writer: BufWriter<fs::File>,
+    target_generation: u64,
+    // previous generations, which will be removed once compacted
+    gen_list: Vec<u64>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Compactor {
+    /// Returns whether the compaction completed, rather than being cancelled.
+    fn run(mut self) -> Result<bool> {
+        // Step 2) Copy the latest value of every key which lives in a previous generation
+        // to the new compaction target file.
+        let namespaces = self.namespaces.read().unwrap().all();
+        // the hint file is written along with the compaction target, so it's ready when the target is blessed
+        let mut hint_writer = get_write_handle(
+            &self.path,
+            self.target_generation,
+            LogFileType::TemporaryHint,
+            self.reader.options.write_buffer_size,
+        )?;
+        // the previous logs created the namespaces, so the compacted log starts by creating them again
+        let created_namespaces: Vec<_> = namespaces
+            .iter()
+            .filter(|(namespace, _, _)| *namespace != DEFAULT_NAMESPACE)
+            .map(|(namespace, name, _)| (*namespace, name.clone()))
+            .collect();
+        let mut current_pos = FILE_HEADER_LEN;
+        for (id, name) in &created_namespaces {
+            current_pos += Command::CreateNamespace {
+                id: *id,
+                name: name.clone(),
+            }
+            .to_writer(&mut self.writer, &self.reader.codec)?;
+        }
+        NamespacesHint {
+            namespaces: created_namespaces,
+        }
+        .to_writer(&mut hint_writer, &self.reader.codec.cipher)?;
+        let mut compacted_entries = Vec::with_capacity(namespaces.len());
+        for (namespace, _, map) in namespaces {
+            let live_entries = map.read().unwrap().entries_before(self.target_generation);
+            let mut compacted_namespace_entries = Vec::with_capacity(live_entries.len());
+            for (key, entry) in live_entries {
+                if self.cancelled.load(Ordering::SeqCst) {
+                    // previous logs are untouched, so dropping the compaction target is all there is to do
+                    drop(self.writer);
+                    drop(hint_writer);
+                    for log_file_type in [LogFileType::Temporary, LogFileType::TemporaryHint] {
+                        fs::remove_file(log_path(
+                            &self.path,
+                            self.target_generation,
+                            log_file_type,
+                        ))?;
+                    }
+                    return Ok(false);
+                }
+                // keys written by a batch are compacted into single sets, so the batch's other keys aren't copied along
+                let cmd = match self.reader.read_command(&entry)?.value_of(&key) {
+                    Some(value) => Command::set(key.clone(), value, entry.expires_at),
+                    None => bail!("Index entry for {:?} points to a removal", key),
+                };
+                let compacted_entry = LogEntry {
+                    generation: self.target_generation,
+                    file_pos: current_pos,
+                    estimated_bytes: entry.estimated_bytes,
+                    expires_at: entry.expires_at,
+                    seq: entry.seq,
+                };
+                current_pos += Command::in_namespace(namespace, cmd)
+                    .to_writer(&mut self.writer, &self.reader.codec)?;
+                Hint {
+                    key: key.clone(),
+                    generation: compacted_entry.generation,
+                    file_pos: compacted_entry.file_pos,
+                    estimated_bytes: compacted_entry.estimated_bytes,
+                    expires_at: compacted_entry.expires_at,
+                    namespace,
+                }
+                .to_writer(&mut hint_writer, &self.reader.codec.cipher)?;
+                compacted_namespace_entries.push((key, entry, compacted_entry));
+            }
+            compacted_entries.push((map, compacted_namespace_entries));
+        }
+        // the previous logs are about to be removed, so the compacted data must really be on disk
+        for writer in [&mut self.writer, &mut hint_writer] {
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        drop(self.writer);
+        drop(hint_writer);
+
+        // Step 3) now all previous logs are compacted into target_generation, so bless that file by renaming it
+        // and point the in-memory maps at it. Keys written or removed in the meantime are left alone.
+        // Readers hold the map's read lock while reading, so once the write lock is released here,
+        // nobody will read from the previous logs anymore (except live snapshots, see step 4).
+        fs::rename(
+            log_path(&self.path, self.target_generation, LogFileType::Temporary),
+            log_path(&self.path, self.target_generation, LogFileType::Blessed),
+        )?;
+        // the hint file is only blessed after its log, so a hint file never exists without its log
+        fs::rename(
+            log_path(
+                &self.path,
+                self.target_generation,
+                LogFileType::TemporaryHint,
+            ),
+            log_path(&self.path, self.target_generation, LogFileType::Hint),
+        )?;
+        // from now on, the previous logs are orphans which the next open would remove
+        self.manifest
+            .lock()
+            .unwrap()
+            .replace(&self.gen_list, self.target_generation)?;
+        for (map, compacted_namespace_entries) in &compacted_entries {
+            let mut map = map.write().unwrap();
+            for (key, previous_entry, compacted_entry) in compacted_namespace_entries {
+                map.relocate(key, previous_entry, compacted_entry.clone());
+            }
+        }
+        // previous versions seen by live snapshots still point at the previous logs,
+        // so removing them is left to the release of the last snapshot
+        let gen_list = &self.gen_list;
+        let deferred = compacted_entries
+            .iter()
+            .any(|(map, _)| map.write().unwrap().defer_removal(gen_list));
+        self.reader
+            .safe_point
+            .store(self.target_generation, Ordering::SeqCst);
+
+        // Step 4) Previous logs are now obsolete, so remove them.
+        if !deferred {
+            for generation in self.gen_list {
+                remove_generation(&self.path, generation)?;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+enum LogFileType {
+    Temporary, // temporary log file, used during compaction, should not receive active reads or writes
+    Blessed,   // blessed log file, ready for active reads and write
+    TemporaryHint, // temporary hint file, written along with a compaction target
+    Hint,      // hint file of a blessed compacted log file
+}
+
+fn get_write_handle(
+    path: &Path,
+    gen: u64,
+    temporary: LogFileType,
+    buffer_size: usize,
+) -> Result<BufWriter<fs::File>> {
+    let file_path = log_path(path, gen, temporary);
+    let context = format!("Opening file {:?} for writing", file_path.to_str());
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_path)
+        .context(context)?;
+    if file.metadata()?.len() == 0 {
+        write_file_header(&file)?;
+    }
+    Ok(BufWriter::with_capacity(buffer_size, file))
+}
+
+fn get_read_handle(
+    path: &Path,
+    gen: u64,
+    log_file_type: LogFileType,
+    buffer_size: usize,
+) -> Result<BufReader<fs::File>> {
+    let file_path = log_path(path, gen, log_file_type);
+    let context = format!("Opening file {:?} for reading", file_path.to_str());
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .open(file_path)
+        .context(context)?;
+    Ok(BufReader::with_capacity(buffer_size, file))
+}
+
+/// Returns the format version of the log file of a generation.
+fn format_version(path: &Path, gen: u64) -> Result<u32> {
+    let mut reader = get_read_handle(path, gen, LogFileType::Blessed, FILE_HEADER_LEN as usize)?;
+    let file_len = reader.get_ref().metadata()?.len();
+    // a file without any record gets its header when it is opened for writing
+    Ok(read_file_header(&mut reader, file_len)?.map_or(FORMAT_VERSION, |(version, _)| version))
+}
+
+/// Removes the log file of a generation and its hint file, if they are still there.
+fn remove_generation(path: &Path, gen: u64) -> Result<()> {
+    for log_file_type in [LogFileType::Blessed, LogFileType::Hint] {
+        match fs::remove_file(log_path(path, gen, log_file_type)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Cuts a log file short at `len`, dropping a torn write at its end.
+fn truncate_log(path: &Path, gen: u64, len: u64) -> Result<()> {
+    let file_path = log_path(path, gen, LogFileType::Blessed);
+    let context = format!(
+        "Truncating torn write at the end of {:?}",
+        file_path.to_str()
+    );
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .open(file_path)
+        .context(context.clone())?;
+    file.set_len(len).context(context.clone())?;
+    file.sync_data().context(context)?;
+    Ok(())
+}
+
+fn log_path(dir: &Path, gen: u64, log_file_type: LogFileType) -> PathBuf {
+    let file_extension = match log_file_type {
+        LogFileType::Temporary => "tmp",
+        LogFileType::Blessed => "log",
+        LogFileType::TemporaryHint => "hint.tmp",
+        LogFileType::Hint => "hint",
+    };
+    dir.join(format!("{}.{}", gen, file_extension))
+}
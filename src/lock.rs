@@ -0,0 +1,99 @@
+use super::Result;
+use anyhow::{bail, Context};
+use fs2::FileExt;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+const LOCK_FILE_NAME: &str = "LOCK";
+
+/// DirLock is an advisory lock on a store directory, held through its `LOCK` file for as long as the store is open.
+///
+/// A writable store takes an exclusive lock, and writes its PID into the file so that other processes can tell
+/// who holds it. Read-only stores take a shared lock, so that any number of them can read the store at once,
+/// but never while a process is writing to it. The lock is released when the file is closed, which the OS
+/// also does for a process which crashed.
+#[derive(Debug)]
+pub struct DirLock {
+    file: fs::File,
+    // the lock file created by this lock, if there was none
+    created: Option<PathBuf>,
+}
+
+impl DirLock {
+    /// Takes the exclusive lock of the store at `path`, creating the lock file if needed.
+    pub fn exclusive(path: &Path) -> Result<Self> {
+        let lock_path = path.join(LOCK_FILE_NAME);
+        let created = !lock_path.exists();
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)
+            .context("Opening lock file")?;
+        if file.try_lock_exclusive().is_err() {
+            if file.try_lock_shared().is_ok() {
+                // nobody writes to the store, it is only being read
+                let _ = fs2::FileExt::unlock(&file);
+                bail!(
+                    "Store at {:?} is locked: it is opened read-only by another process",
+                    path
+                );
+            }
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            bail!(
+                "Store at {:?} is locked: it is already opened by process {}",
+                path,
+                holder.trim()
+            );
+        }
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+        file.sync_data()?;
+        Ok(Self {
+            file,
+            created: if created { Some(lock_path) } else { None },
+        })
+    }
+
+    /// Takes a shared lock of the store at `path`, for reading it. Returns None if the store has no lock file,
+    /// since creating one would modify the store: no process is writing to such a store.
+    pub fn shared(path: &Path) -> Result<Option<Self>> {
+        let mut file = match fs::File::open(path.join(LOCK_FILE_NAME)) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        if file.try_lock_shared().is_err() {
+            let mut holder = String::new();
+            file.read_to_string(&mut holder)?;
+            bail!(
+                "Store at {:?} is locked: it is opened for writing by process {}",
+                path,
+                holder.trim()
+            );
+        }
+        Ok(Some(Self {
+            file,
+            created: None,
+        }))
+    }
+
+    /// Releases the lock of a store which failed to open, removing the lock file if this lock created it,
+    /// so that the directory is left as it was found. The file is removed while still locked, so no other
+    /// process can take the lock through it in the meantime.
+    pub fn abandon(mut self) {
+        if let Some(lock_path) = self.created.take() {
+            let _ = fs::remove_file(lock_path);
+        }
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = fs2::FileExt::unlock(&self.file);
+    }
+}
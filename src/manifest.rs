@@ -0,0 +1,154 @@
+use super::record::{read_framed, write_record, Codec};
+use super::Result;
+use anyhow::Context;
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const TEMPORARY_MANIFEST_FILE_NAME: &str = "MANIFEST.tmp";
+
+/// Manifest is the set of live generations of a store, kept in its `MANIFEST` file, along with whether
+/// every record of the store is encrypted.
+///
+/// The file is only ever replaced atomically: the new set is written to `MANIFEST.tmp`, synced, renamed over
+/// `MANIFEST`, and the directory is synced. Log and hint files which are not listed in the manifest are leftovers
+/// of a compaction interrupted by a crash (or of previous generations which it didn't get to remove),
+/// and are removed when the store is opened.
+#[derive(Debug)]
+pub struct Manifest {
+    path: Arc<PathBuf>,
+    codec: Arc<Codec>,
+    generations: BTreeSet<u64>,
+    encrypted: bool,
+}
+
+impl Manifest {
+    /// Reads the manifest of the store at `path`. Stores written before manifests existed don't have one,
+    /// so their generations are listed from the names of their log files instead. A brand new store is
+    /// encrypted if `codec` encrypts its records.
+    pub fn load(path: Arc<PathBuf>, codec: Arc<Codec>) -> Result<Self> {
+        let (generations, encrypted) = match fs::read(path.join(MANIFEST_FILE_NAME)) {
+            // manifests written before the encrypted flag existed only hold the generations
+            Ok(manifest) => match read_framed(&manifest[..], &codec.cipher) {
+                Ok(manifest) => manifest,
+                Err(_) => (
+                    read_framed(&manifest[..], &codec.cipher).context("Reading manifest")?,
+                    false,
+                ),
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let generations: BTreeSet<_> = sorted_gen_list(&path)?.into_iter().collect();
+                let encrypted = generations.is_empty() && codec.cipher.is_enabled();
+                (generations, encrypted)
+            }
+            Err(err) => return Err(err).context("Opening manifest"),
+        };
+        Ok(Self {
+            path,
+            codec,
+            generations,
+            encrypted,
+        })
+    }
+
+    /// Whether every record of the store is encrypted.
+    pub fn encrypted(&self) -> bool {
+        self.encrypted
+    }
+
+    /// Returns the live generations, in order.
+    pub fn generations(&self) -> Vec<u64> {
+        self.generations.iter().copied().collect()
+    }
+
+    /// Records that `generation` is live.
+    pub fn add(&mut self, generation: u64) -> Result<()> {
+        self.generations.insert(generation);
+        self.write()
+    }
+
+    /// Records that `compacted` generations were replaced by `generation`.
+    pub fn replace(&mut self, compacted: &[u64], generation: u64) -> Result<()> {
+        for compacted_generation in compacted {
+            self.generations.remove(compacted_generation);
+        }
+        self.generations.insert(generation);
+        // compaction rewrites every generation but the one taking new writes, and both are written with the key
+        self.encrypted |= self.codec.cipher.is_enabled();
+        self.write()
+    }
+
+    /// Removes the log and hint files of generations which are not live, and temporary files.
+    pub fn remove_orphans(&self) -> Result<()> {
+        for entry in fs::read_dir(&*self.path)? {
+            let file_path = entry?.path();
+            let file_name = match file_path.file_name().and_then(OsStr::to_str) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            let orphan = match file_name.split_once('.') {
+                Some(("MANIFEST", "tmp")) => true,
+                Some((generation, extension)) => match generation.parse::<u64>() {
+                    Ok(generation) => match extension {
+                        "log" | "hint" => !self.generations.contains(&generation),
+                        "tmp" | "hint.tmp" => true,
+                        _ => false,
+                    },
+                    Err(_) => false,
+                },
+                None => false,
+            };
+            if orphan {
+                fs::remove_file(&file_path)
+                    .with_context(|| format!("Removing orphaned file {:?}", file_path))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&self) -> Result<()> {
+        let temporary_path = self.path.join(TEMPORARY_MANIFEST_FILE_NAME);
+        let mut file = fs::File::create(&temporary_path).context("Writing manifest")?;
+        write_record(
+            &mut file,
+            &(&self.generations, self.encrypted),
+            &self.codec.cipher,
+        )?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, self.path.join(MANIFEST_FILE_NAME))?;
+        sync_dir(&self.path)
+    }
+}
+
+/// Syncs a directory, so that the files renamed in it are durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    fs::File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Returns sorted generation numbers in the given directory.
+pub fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
@@ -0,0 +1,94 @@
+use super::internal_map::InternalMap;
+use super::Result;
+use anyhow::bail;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+
+/// Id of the default namespace, which always exists. Its commands are logged without a namespace,
+/// just like before namespaces existed.
+pub const DEFAULT_NAMESPACE: u32 = 0;
+/// Name of the default namespace.
+pub const DEFAULT_NAMESPACE_NAME: &str = "default";
+
+/// Namespaces is the registry of the namespaces of a store, which share the same log files,
+/// but each have their own in-memory map.
+#[derive(Debug)]
+pub struct Namespaces {
+    by_id: BTreeMap<u32, Namespace>,
+    // ids are never reused while the store is open, so that handles to a dropped namespace can't
+    // write to a namespace created later
+    next_id: u32,
+}
+
+#[derive(Debug)]
+struct Namespace {
+    name: String,
+    map: Arc<RwLock<InternalMap>>,
+}
+
+impl Namespaces {
+    pub fn new() -> Self {
+        let mut namespaces = Self {
+            by_id: BTreeMap::new(),
+            next_id: DEFAULT_NAMESPACE,
+        };
+        namespaces.create(DEFAULT_NAMESPACE, DEFAULT_NAMESPACE_NAME.to_owned());
+        namespaces
+    }
+    /// Registers the namespace `id`, with an empty map. Does nothing if it already exists,
+    /// since replay may come across the same namespace in a compacted log and a previous one.
+    pub fn create(&mut self, id: u32, name: String) {
+        self.next_id = self.next_id.max(id + 1);
+        self.by_id.entry(id).or_insert_with(|| Namespace {
+            name,
+            map: Arc::new(RwLock::new(InternalMap::new())),
+        });
+    }
+    /// Id to give to the next namespace created.
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+    pub fn id_of(&self, name: &str) -> Option<u32> {
+        self.by_id
+            .iter()
+            .find(|(_, namespace)| namespace.name == name)
+            .map(|(&id, _)| id)
+    }
+    /// Returns whether the namespace `id` exists.
+    pub fn contains(&self, id: u32) -> bool {
+        self.by_id.contains_key(&id)
+    }
+    /// Returns the map of the namespace `id`.
+    pub fn map(&self, id: u32) -> Result<Arc<RwLock<InternalMap>>> {
+        match self.by_id.get(&id) {
+            Some(namespace) => Ok(Arc::clone(&namespace.map)),
+            None => bail!("Namespace was dropped"),
+        }
+    }
+    /// Removes the namespace `id` and all of its keys, which handles to the namespace will no longer see.
+    /// Returns estimate of wasted bytes detected.
+    pub fn remove(&mut self, id: u32) -> Result<usize> {
+        match self.by_id.remove(&id) {
+            Some(namespace) => {
+                let mut map = namespace.map.write().unwrap();
+                map.next_seq();
+                Ok(map.clear())
+            }
+            None => bail!("Namespace was dropped"),
+        }
+    }
+    /// Names of all the namespaces, in the order they were created.
+    pub fn names(&self) -> Vec<String> {
+        self.by_id
+            .values()
+            .map(|namespace| namespace.name.clone())
+            .collect()
+    }
+    /// Returns the id, name and map of every namespace.
+    pub fn all(&self) -> Vec<(u32, String, Arc<RwLock<InternalMap>>)> {
+        self.by_id
+            .iter()
+            .map(|(&id, namespace)| (id, namespace.name.clone(), Arc::clone(&namespace.map)))
+            .collect()
+    }
+}
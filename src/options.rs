@@ -0,0 +1,179 @@
+use super::encryption::EncryptionKey;
+use super::{KvStore, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Options and flags which can be used to configure how a `KvStore` is opened.
+///
+/// Obtained with `KvStore::builder()` (or `OpenOptions::new()`), which starts with the same defaults as `KvStore::open`.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, SyncPolicy};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::builder()
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::Always)
+///     .open(current_dir()?)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    pub(crate) compaction_threshold: usize,
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) create_if_missing: bool,
+    pub(crate) error_if_exists: bool,
+    pub(crate) read_only: bool,
+    pub(crate) read_buffer_size: usize,
+    pub(crate) write_buffer_size: usize,
+    pub(crate) compression: Compression,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) decryption_keys: Vec<EncryptionKey>,
+    pub(crate) migrate_to_encryption: bool,
+}
+
+/// How hard `set` and `remove` try to make a write durable before returning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Writes are flushed to the OS, but never synced to disk. Acknowledged writes can be lost on power failure.
+    Never,
+    /// Every write is synced to disk before returning.
+    Always,
+    /// Every write is synced to disk before returning, but writers waiting at the same time share a single sync.
+    GroupCommit,
+    /// Writes are synced to disk in the background at the given interval. At most one interval of acknowledged
+    /// writes can be lost on power failure.
+    Periodic(Duration),
+}
+
+/// How the records written to the logs are compressed.
+///
+/// The compression of each record is kept in its header, so a store can be reopened with a different compression:
+/// records already written stay readable, and compaction rewrites them with the new one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// Records are written as is.
+    None,
+    /// Records are compressed with LZ4, which is very fast but saves less space.
+    Lz4,
+    /// Records are compressed with Zstandard, which is slower but saves more space.
+    Zstd,
+}
+
+const DEFAULT_COMPACTION_THRESHOLD: usize = 1024 * 1024; // 1MB wasted space (very eager compaction)
+const DEFAULT_BUFFER_SIZE: usize = 8 * 1024; // same as std::io::BufReader and std::io::BufWriter
+
+impl OpenOptions {
+    /// Creates a blank new set of options, with the defaults used by `KvStore::open`.
+    pub fn new() -> Self {
+        Self {
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            sync_policy: SyncPolicy::Never,
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+            read_buffer_size: DEFAULT_BUFFER_SIZE,
+            write_buffer_size: DEFAULT_BUFFER_SIZE,
+            compression: Compression::None,
+            encryption_key: None,
+            decryption_keys: Vec::new(),
+            migrate_to_encryption: false,
+        }
+    }
+
+    /// Sets how many bytes of stale data may pile up in the logs before a compaction is started.
+    pub fn compaction_threshold(&mut self, bytes: usize) -> &mut Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Sets the `SyncPolicy` used by writes. Defaults to `SyncPolicy::Never`.
+    pub fn sync_policy(&mut self, sync_policy: SyncPolicy) -> &mut Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets whether a new store should be created if none exists at the path. Defaults to true.
+    pub fn create_if_missing(&mut self, create_if_missing: bool) -> &mut Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Sets whether opening should fail if a store already exists at the path. Defaults to false.
+    pub fn error_if_exists(&mut self, error_if_exists: bool) -> &mut Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Sets whether the store is opened read-only. A read-only store never creates or modifies any file,
+    /// and returns an error on `set` and `remove`. Defaults to false.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets the buffer size (in bytes) of each reader handle.
+    pub fn read_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        self.read_buffer_size = bytes;
+        self
+    }
+
+    /// Sets the buffer size (in bytes) of the writer handle.
+    pub fn write_buffer_size(&mut self, bytes: usize) -> &mut Self {
+        self.write_buffer_size = bytes;
+        self
+    }
+
+    /// Sets the `Compression` of the records written to the logs. Defaults to `Compression::None`.
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Sets the 256 bit key used to encrypt the records written to the logs (and hint files), with ChaCha20-Poly1305.
+    /// `id` is stored next to every record it encrypts, and tells which key a record needs when it is read back.
+    /// Defaults to no encryption.
+    ///
+    /// Opening a store with a key which doesn't match its records fails, and so does opening an encrypted store
+    /// holding records written without encryption, which nobody with the key could have written. An existing
+    /// store is encrypted by opening it with a key and `migrate_to_encryption`, and compacting it.
+    ///
+    /// To rotate the key, open the store with the new key, the previous one as a `decryption_key`,
+    /// and call `KvStore::compact`: afterwards, every record is encrypted with the new key.
+    pub fn encryption_key(&mut self, id: u32, key: [u8; 32]) -> &mut Self {
+        self.encryption_key = Some(EncryptionKey { id, bytes: key });
+        self
+    }
+
+    /// Sets whether a store which isn't encrypted yet can be opened with an `encryption_key`. Its records written
+    /// without encryption are then read as is, until `KvStore::compact` rewrites them all with the key:
+    /// from then on, the store is marked as encrypted, and records written without encryption are rejected.
+    /// Defaults to false, so that opening a store which isn't encrypted with a key fails.
+    pub fn migrate_to_encryption(&mut self, migrate_to_encryption: bool) -> &mut Self {
+        self.migrate_to_encryption = migrate_to_encryption;
+        self
+    }
+
+    /// Adds a key which records can be decrypted with, but which is never used to encrypt new records.
+    pub fn decryption_key(&mut self, id: u32, key: [u8; 32]) -> &mut Self {
+        self.decryption_keys.push(EncryptionKey { id, bytes: key });
+        self
+    }
+
+    /// Opens a `KvStore` at `path` with the options specified by `self`.
+    ///
+    /// # Errors
+    ///
+    /// On top of the errors of `KvStore::open`, this fails if the store is missing and `create_if_missing`
+    /// is false, or if the store exists and `error_if_exists` is true.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path.into(), self.clone())
+    }
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,67 @@
+use super::command::Command;
+use super::Result;
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// Messages larger than this are rejected, so that a bad length prefix can't make the other end allocate
+/// an arbitrary amount of memory.
+const MAX_MESSAGE_LEN: u32 = 64 * 1024 * 1024;
+
+/// A request sent by `KvsClient` to `KvsServer`. Writes are sent as the `Command` the store logs for them.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Get {
+        #[serde(with = "serde_bytes")]
+        key: Vec<u8>,
+    },
+    Write(Command),
+}
+
+/// The response of `KvsServer` to a `Request`: the value looked up by a get (None for a write),
+/// or the error the request failed with.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Ok(#[serde(with = "serde_bytes")] Option<Vec<u8>>),
+    Err(String),
+}
+
+/// Writes `message` as its length (a little endian u32) followed by its bincode encoding.
+pub fn write_message<W, T>(mut writer: W, message: &T) -> Result<()>
+where
+    W: Write,
+    T: Serialize,
+{
+    let payload = bincode::serialize(message)?;
+    if payload.len() > MAX_MESSAGE_LEN as usize {
+        bail!("Message of {} bytes is too large", payload.len());
+    }
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a message written by `write_message`, returning None if the connection was closed before it started.
+pub fn read_message<R, T>(mut reader: R) -> Result<Option<T>>
+where
+    R: Read,
+    T: DeserializeOwned,
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_le_bytes(len);
+    if len > MAX_MESSAGE_LEN {
+        bail!("Message of {} bytes is too large", len);
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).context("Reading message")?;
+    Ok(Some(
+        bincode::deserialize(&payload).context("Decoding message")?,
+    ))
+}
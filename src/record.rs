@@ -0,0 +1,182 @@
+use super::compression::{decompress, from_flag, to_flag, Compressor};
+use super::encryption::Cipher;
+use super::{Compression, OpenOptions, Result};
+use anyhow::{bail, Context};
+use bincode::{deserialize, serialize};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::Read;
+use std::io::Write;
+
+/// Every record in a log (or hint) file is framed by a header holding the length of the payload and a CRC32
+/// checksum (both little endian u32). The checksum covers the length as well as the payload, so that a zeroed
+/// out or garbage header never passes as a valid record.
+///
+/// The top three bits of the length are flags: the top two hold the `Compression` of the payload, and the next one
+/// whether it is encrypted. Records written before these flags existed (or by stores opened without compression
+/// and encryption) read as plain records, unless the store is encrypted. The flags are authenticated along with
+/// encrypted payloads.
+const HEADER_LEN: u64 = 8;
+const FLAGS_SHIFT: u32 = 29;
+const ENCRYPTED_FLAG: u32 = 1;
+const LEN_MASK: u32 = (1 << FLAGS_SHIFT) - 1;
+
+/// Outcome of reading the next record of a file during replay.
+#[derive(Debug)]
+pub enum Record<T> {
+    /// A valid record, along with its length on disk.
+    Valid(T, u64),
+    /// Clean end of the file.
+    Eof,
+    /// The file ends with an incomplete or damaged record, which can only be a write torn by a crash.
+    Torn,
+}
+
+/// How the payloads of log records are encoded: compressed, then encrypted.
+#[derive(Debug)]
+pub struct Codec {
+    pub compressor: Compressor,
+    pub cipher: Cipher,
+}
+
+impl Codec {
+    pub fn new(options: &OpenOptions) -> Self {
+        Self {
+            compressor: Compressor::new(options.compression),
+            cipher: Cipher::new(options.encryption_key.as_ref(), &options.decryption_keys),
+        }
+    }
+}
+
+/// Writes `value` as a single framed record (encrypted by `cipher` if enabled), returning the number of bytes written.
+pub fn write_record<T, W>(writer: W, value: &T, cipher: &Cipher) -> Result<u64>
+where
+    T: Serialize,
+    W: Write,
+{
+    write_payload(writer, Compression::None, serialize(value)?, cipher)
+}
+
+/// Writes `value` as a single framed record encoded by `codec`, returning the number of bytes written.
+pub fn write_compressed_record<T, W>(writer: W, value: &T, codec: &Codec) -> Result<u64>
+where
+    T: Serialize,
+    W: Write,
+{
+    let (compression, payload) = codec.compressor.compress(serialize(value)?)?;
+    write_payload(writer, compression, payload, &codec.cipher)
+}
+
+fn write_payload<W>(
+    mut writer: W,
+    compression: Compression,
+    payload: Vec<u8>,
+    cipher: &Cipher,
+) -> Result<u64>
+where
+    W: Write,
+{
+    let mut flags = to_flag(compression) << 1;
+    let payload = if cipher.is_enabled() {
+        flags |= ENCRYPTED_FLAG;
+        cipher.encrypt(&[flags as u8], &payload)?
+    } else {
+        payload
+    };
+    if payload.len() > LEN_MASK as usize {
+        bail!("Record of {} bytes is too large", payload.len());
+    }
+    let len = (payload.len() as u32 | flags << FLAGS_SHIFT).to_le_bytes();
+    writer.write_all(&len)?;
+    writer.write_all(&checksum(&len, &payload).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(HEADER_LEN + payload.len() as u64)
+}
+
+/// Reads a single framed record, failing if it is incomplete or its checksum doesn't match.
+pub fn read_framed<T, R>(mut reader: R, cipher: &Cipher) -> Result<T>
+where
+    T: DeserializeOwned,
+    R: Read,
+{
+    let mut header = [0; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let (len, flags, crc) = parse_header(&header);
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    if checksum(&header[..4], &payload) != crc {
+        bail!("Checksum mismatch");
+    }
+    Ok(deserialize(&decode(flags, payload, cipher)?)?)
+}
+
+/// Reads the next record from `reader`, which is positioned at `pos` in a file of `file_len` bytes.
+///
+/// A record which is cut short by the end of the file, or the very last record of the file failing its
+/// checksum, is reported as `Record::Torn`. A checksum failure anywhere else is real corruption, and is
+/// returned as an error naming the offset of the record. So is a record which can't be decrypted.
+pub fn read_record<T, R>(
+    mut reader: R,
+    pos: u64,
+    file_len: u64,
+    cipher: &Cipher,
+) -> Result<Record<T>>
+where
+    T: DeserializeOwned,
+    R: Read,
+{
+    let remaining = file_len.saturating_sub(pos);
+    if remaining == 0 {
+        return Ok(Record::Eof);
+    }
+    if remaining < HEADER_LEN {
+        return Ok(Record::Torn);
+    }
+    let mut header = [0; HEADER_LEN as usize];
+    reader.read_exact(&mut header)?;
+    let (len, flags, crc) = parse_header(&header);
+    let record_len = HEADER_LEN + len as u64;
+    if record_len > remaining {
+        return Ok(Record::Torn);
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    if checksum(&header[..4], &payload) != crc {
+        if record_len == remaining {
+            return Ok(Record::Torn);
+        }
+        bail!("Corrupted record at offset {}: checksum mismatch", pos);
+    }
+    let payload = decode(flags, payload, cipher)
+        .with_context(|| format!("Decoding record at offset {}", pos))?;
+    match deserialize(&payload) {
+        Ok(value) => Ok(Record::Valid(value, record_len)),
+        Err(err) => bail!("Corrupted record at offset {}: {}", pos, err),
+    }
+}
+
+/// Decrypts (if needed) and decompresses a payload whose header holds `flags`.
+fn decode(flags: u32, payload: Vec<u8>, cipher: &Cipher) -> Result<Vec<u8>> {
+    let payload = if flags & ENCRYPTED_FLAG != 0 {
+        cipher.decrypt(&[flags as u8], &payload)?
+    } else if cipher.rejects_plaintext() {
+        bail!("Record is not encrypted, but the store is");
+    } else {
+        payload
+    };
+    decompress(from_flag(flags >> 1)?, payload)
+}
+
+/// Returns the payload length, flags and checksum held by a header.
+fn parse_header(header: &[u8; HEADER_LEN as usize]) -> (u32, u32, u32) {
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    (len & LEN_MASK, len >> FLAGS_SHIFT, crc)
+}
+
+fn checksum(len: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(len);
+    hasher.update(payload);
+    hasher.finalize()
+}
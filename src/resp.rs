@@ -0,0 +1,471 @@
+use super::server::wait_for_request;
+use super::{KvStore, Result};
+use anyhow::{bail, Context};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Same limits as Redis: bulk strings of up to 512MB, and inline commands of up to 64KB.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+const MAX_LINE_LEN: u64 = 64 * 1024;
+/// Number of keys examined by a `SCAN` without a `COUNT`.
+const DEFAULT_SCAN_COUNT: usize = 10;
+/// Number of `SCAN` cursors a server remembers, the oldest being forgotten first.
+const MAX_SCAN_CURSORS: usize = 16 * 1024;
+
+/// A RESP2 value, as sent back to Redis clients.
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    /// None is the null bulk string, which stands for a missing value.
+    BulkString(Option<Vec<u8>>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn bulk(bytes: impl Into<Vec<u8>>) -> Self {
+        Value::BulkString(Some(bytes.into()))
+    }
+
+    fn ok() -> Self {
+        Value::SimpleString("OK".to_owned())
+    }
+}
+
+/// The cursors handed out by the `SCAN`s of a server. Redis clients expect cursors to be integers,
+/// so each one stands for the last key a `SCAN` returned, and the next `SCAN` continues after that key.
+/// They are shared by all the connections, since pooled clients may continue a scan on another one.
+#[derive(Debug, Default)]
+pub(crate) struct ScanCursors {
+    last_id: u64,
+    last_keys: BTreeMap<u64, Vec<u8>>,
+}
+
+impl ScanCursors {
+    /// Returns a new cursor, continuing after `key`.
+    fn after(&mut self, key: Vec<u8>) -> u64 {
+        self.last_id += 1;
+        self.last_keys.insert(self.last_id, key);
+        if self.last_keys.len() > MAX_SCAN_CURSORS {
+            self.last_keys.pop_first();
+        }
+        self.last_id
+    }
+
+    /// Returns the lower bound of the keys which remain to be scanned from `cursor`, 0 being the start.
+    fn lower_bound(&self, cursor: u64) -> Result<Bound<Vec<u8>>> {
+        if cursor == 0 {
+            return Ok(Bound::Unbounded);
+        }
+        match self.last_keys.get(&cursor) {
+            Some(key) => Ok(Bound::Excluded(key.clone())),
+            None => bail!("invalid cursor"),
+        }
+    }
+}
+
+/// Serves a connection of a Redis client, answering each of its commands with the `KvStore`.
+pub fn serve_connection(
+    store: &KvStore,
+    cursors: &Mutex<ScanCursors>,
+    stream: TcpStream,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while wait_for_request(&mut reader)? {
+        let args = match read_request(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(err) => {
+                // like Redis, report the protocol error and close the connection, since the rest can't be parsed
+                write_value(
+                    &mut writer,
+                    &Value::Error(format!("ERR Protocol error: {}", err)),
+                )?;
+                writer.flush()?;
+                return Err(err);
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+        let reply = match handle(store, cursors, &args) {
+            Ok(reply) => reply,
+            Err(err) => Value::Error(format!("ERR {:#}", err)),
+        };
+        write_value(&mut writer, &reply)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Reads the arguments of a command, sent either as an array of bulk strings (as Redis clients do),
+/// or as an inline command made of words separated by spaces (as typed in telnet).
+/// Returns None if the connection was closed before the command started.
+fn read_request<R>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>>
+where
+    R: BufRead,
+{
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(u8::is_ascii_whitespace)
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+    let count = parse_len(&line[1..])?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.context("Unexpected end of command")?;
+        if line.first() != Some(&b'$') {
+            bail!("expected '$', got '{}'", String::from_utf8_lossy(&line));
+        }
+        let len = parse_len(&line[1..])?;
+        if len > MAX_BULK_LEN {
+            bail!("invalid bulk length");
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            bail!("bulk string is not terminated by CRLF");
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line terminated by CRLF (or a lone LF, as telnet may send), without its terminator.
+fn read_line<R>(reader: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: BufRead,
+{
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        bail!("too big inline request");
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8]) -> Result<usize> {
+    match std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|len| len.parse().ok())
+    {
+        Some(len) => Ok(len),
+        None => bail!("invalid length"),
+    }
+}
+
+fn write_value<W>(writer: &mut W, value: &Value) -> io::Result<()>
+where
+    W: Write,
+{
+    match value {
+        Value::SimpleString(string) => write!(writer, "+{}\r\n", string),
+        Value::Error(message) => write!(writer, "-{}\r\n", message),
+        Value::Integer(integer) => write!(writer, ":{}\r\n", integer),
+        Value::BulkString(None) => writer.write_all(b"$-1\r\n"),
+        Value::BulkString(Some(bytes)) => {
+            write!(writer, "${}\r\n", bytes.len())?;
+            writer.write_all(bytes)?;
+            writer.write_all(b"\r\n")
+        }
+        Value::Array(values) => {
+            write!(writer, "*{}\r\n", values.len())?;
+            values
+                .iter()
+                .try_for_each(|value| write_value(writer, value))
+        }
+    }
+}
+
+/// Runs the command made of `args`, returning its reply. Errors are sent back to the client as error replies.
+fn handle(store: &KvStore, cursors: &Mutex<ScanCursors>, args: &[Vec<u8>]) -> Result<Value> {
+    let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+    let args = &args[1..];
+    let check_arity = |valid: bool| -> Result<()> {
+        if !valid {
+            bail!("wrong number of arguments for '{}' command", name);
+        }
+        Ok(())
+    };
+    match name.as_str() {
+        "ping" => {
+            check_arity(args.len() <= 1)?;
+            Ok(match args.first() {
+                Some(message) => Value::bulk(message.clone()),
+                None => Value::SimpleString("PONG".to_owned()),
+            })
+        }
+        "get" => {
+            check_arity(args.len() == 1)?;
+            Ok(Value::BulkString(store.get_bytes(&args[0])?))
+        }
+        "set" => {
+            check_arity(args.len() == 2 || args.len() == 4)?;
+            match args.get(2..) {
+                Some([option, ttl]) => {
+                    let ttl = match parse_integer(ttl) {
+                        Ok(ttl) if ttl > 0 => ttl as u64,
+                        _ => bail!("invalid expire time in 'set' command"),
+                    };
+                    let ttl = match option.to_ascii_lowercase().as_slice() {
+                        b"ex" => Duration::from_secs(ttl),
+                        b"px" => Duration::from_millis(ttl),
+                        _ => bail!("syntax error"),
+                    };
+                    store.set_with_ttl(&args[0], &args[1], ttl)?;
+                }
+                _ => store.set_bytes(&args[0], &args[1])?,
+            }
+            Ok(Value::ok())
+        }
+        "del" => {
+            check_arity(!args.is_empty())?;
+            let mut removed = 0;
+            for key in args {
+                if remove(store, key)? {
+                    removed += 1;
+                }
+            }
+            Ok(Value::Integer(removed))
+        }
+        "exists" => {
+            check_arity(!args.is_empty())?;
+            let mut existing = 0;
+            for key in args {
+                if store.get_bytes(key)?.is_some() {
+                    existing += 1;
+                }
+            }
+            Ok(Value::Integer(existing))
+        }
+        "keys" => {
+            check_arity(args.len() == 1)?;
+            let pattern = &args[0];
+            let mut keys = Vec::new();
+            // only the keys starting with the literal start of the pattern can match it
+            for key in store.scan_prefix(literal_prefix(pattern)).keys() {
+                if glob_match(pattern, &key) {
+                    keys.push(Value::bulk(key));
+                }
+            }
+            Ok(Value::Array(keys))
+        }
+        "scan" => {
+            check_arity(!args.is_empty() && args.len() % 2 == 1)?;
+            let cursor = match parse_integer(&args[0]) {
+                Ok(cursor) if cursor >= 0 => cursor as u64,
+                _ => bail!("invalid cursor"),
+            };
+            let mut pattern = None;
+            let mut count = DEFAULT_SCAN_COUNT;
+            for option in args[1..].chunks(2) {
+                match option[0].to_ascii_lowercase().as_slice() {
+                    b"match" => pattern = Some(&option[1]),
+                    b"count" => match parse_integer(&option[1]) {
+                        Ok(value) if value > 0 => count = value as usize,
+                        _ => bail!("syntax error"),
+                    },
+                    _ => bail!("syntax error"),
+                }
+            }
+            scan(store, cursors, cursor, pattern.map(Vec::as_slice), count)
+        }
+        "expire" => {
+            check_arity(args.len() == 2)?;
+            let seconds = parse_integer(&args[1])?;
+            // like Redis, a ttl which isn't positive removes the key right away
+            let exists = if seconds > 0 {
+                store.expire(&args[0], Duration::from_secs(seconds as u64))?
+            } else {
+                remove(store, &args[0])?
+            };
+            Ok(Value::Integer(exists as i64))
+        }
+        "incr" => {
+            check_arity(args.len() == 1)?;
+            loop {
+                let current = store.get_bytes(&args[0])?;
+                let value = match &current {
+                    Some(current) => parse_integer(current)?,
+                    None => 0,
+                };
+                let value = match value.checked_add(1) {
+                    Some(value) => value,
+                    None => bail!("increment or decrement would overflow"),
+                };
+                let new = value.to_string();
+                // like Redis, the counter keeps its time-to-live; retry if another client changed the value
+                // in the meantime
+                if store.compare_and_swap_keeping_ttl(
+                    &args[0],
+                    current.as_deref(),
+                    new.as_bytes(),
+                )? {
+                    return Ok(Value::Integer(value));
+                }
+            }
+        }
+        _ => bail!("unknown command '{}'", name),
+    }
+}
+
+/// Removes `key`, returning whether it existed.
+fn remove(store: &KvStore, key: &[u8]) -> Result<bool> {
+    loop {
+        match store.get_bytes(key)? {
+            None => return Ok(false),
+            // retry if another client changed the value in the meantime
+            Some(value) => {
+                if store.remove_if_equals(key, &value)? {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+}
+
+/// Returns the keys after `cursor` in key order, examining `count` keys, and the cursor to continue from
+/// (or 0 once all keys were examined). Only the index is walked, values are never read.
+fn scan(
+    store: &KvStore,
+    cursors: &Mutex<ScanCursors>,
+    cursor: u64,
+    pattern: Option<&[u8]>,
+    count: usize,
+) -> Result<Value> {
+    let lower = cursors.lock().unwrap().lower_bound(cursor)?;
+    let mut remaining = store.scan((lower, Bound::Unbounded)).keys();
+    let mut keys = Vec::new();
+    let mut last_key = None;
+    for key in remaining.by_ref().take(count) {
+        if pattern.is_none_or(|pattern| glob_match(pattern, &key)) {
+            keys.push(Value::bulk(key.clone()));
+        }
+        last_key = Some(key);
+    }
+    let next_cursor = match (last_key, remaining.next()) {
+        (Some(last_key), Some(_)) => cursors.lock().unwrap().after(last_key),
+        _ => 0,
+    };
+    Ok(Value::Array(vec![
+        Value::bulk(next_cursor.to_string()),
+        Value::Array(keys),
+    ]))
+}
+
+fn parse_integer(bytes: &[u8]) -> Result<i64> {
+    match std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|integer| integer.parse().ok())
+    {
+        Some(integer) => Ok(integer),
+        None => bail!("value is not an integer or out of range"),
+    }
+}
+
+/// Returns the start of `pattern` which has no special characters.
+fn literal_prefix(pattern: &[u8]) -> &[u8] {
+    let end = pattern
+        .iter()
+        .position(|c| matches!(c, b'*' | b'?' | b'[' | b'\\'))
+        .unwrap_or(pattern.len());
+    &pattern[..end]
+}
+
+/// Matches `key` against a glob-style pattern, with the syntax of Redis: `*` matches any bytes, `?` any byte,
+/// `[abc]`, `[^abc]` and `[a-c]` a byte in (or not in) a set, and `\` escapes the next byte.
+///
+/// Only the last `*` is ever backtracked to, which lets it match one more byte: this is enough since every
+/// other element matches a single byte, and keeps patterns like `*a*a*a*b` from taking exponential time.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // position in the pattern after the last `*`, and in the key where what it matches ends
+    let mut last_star = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            last_star = Some((p, k));
+            continue;
+        }
+        if let Some(len) = match_byte(&pattern[p..], key[k]) {
+            p += len;
+            k += 1;
+            continue;
+        }
+        match last_star {
+            Some((star_p, star_k)) => {
+                p = star_p;
+                k = star_k + 1;
+                last_star = Some((star_p, k));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches the byte `c` against the first element of `pattern`, which isn't a `*`.
+/// Returns the length of the element if it matches.
+fn match_byte(pattern: &[u8], c: u8) -> Option<usize> {
+    let (matched, len) = match pattern {
+        [] => return None,
+        [b'?', ..] => (true, 1),
+        [b'[', set @ ..] => {
+            let (negate, mut rest) = match set {
+                [b'^', rest @ ..] => (true, rest),
+                _ => (false, set),
+            };
+            let mut matched = false;
+            loop {
+                match rest {
+                    // like Redis, an unterminated set ends with the pattern
+                    [] => break,
+                    [b']', after @ ..] => {
+                        rest = after;
+                        break;
+                    }
+                    [b'\\', escaped, after @ ..] => {
+                        matched |= *escaped == c;
+                        rest = after;
+                    }
+                    [low, b'-', high, after @ ..] if *high != b']' => {
+                        matched |= (*low.min(high)..=*low.max(high)).contains(&c);
+                        rest = after;
+                    }
+                    [other, after @ ..] => {
+                        matched |= *other == c;
+                        rest = after;
+                    }
+                }
+            }
+            (matched != negate, pattern.len() - rest.len())
+        }
+        [b'\\', escaped, ..] => (*escaped == c, 2),
+        [other, ..] => (*other == c, 1),
+    };
+    if matched {
+        Some(len)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,165 @@
+use super::command::Command;
+use super::http;
+use super::protocol::{read_message, write_message, Request, Response};
+use super::resp::{self, ScanCursors};
+use super::thread_pool::{NaiveThreadPool, ThreadPool};
+use super::{KvStore, Result};
+use anyhow::bail;
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Connections which send no request for this long are closed once a `ThreadPool` is set, unless
+/// `KvsServer::idle_timeout` says otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The protocol spoken by a `KvsServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Length-prefixed bincode messages, as sent by `KvsClient`.
+    Kvs,
+    /// RESP2, the protocol of Redis, for the commands GET, SET (with EX or PX), DEL, EXISTS, KEYS, SCAN, EXPIRE,
+    /// INCR and PING. This lets `redis-cli` and Redis client libraries talk to the server.
+    Resp,
+    /// HTTP/1.1, with `GET`, `PUT` and `DELETE` on `/keys/{key}`, the listing of keys on `/keys`,
+    /// and `/health` and `/stats` endpoints. Values are sent as is, everything else as JSON.
+    Http,
+}
+
+/// KvsServer serves a `KvStore` over TCP, to any number of `KvsClient`s (or Redis or HTTP clients).
+///
+/// Each connection is handled as a job of the server's `ThreadPool`, with its own handle to the store,
+/// and can send any number of requests, each answered before the next one is read. A connection keeps
+/// its thread until it is closed, so a pool of N threads serves at most N connections at a time: this is
+/// why connections which stay idle for longer than the idle timeout are closed, handing their thread over
+/// to the connections waiting for one. With the default `NaiveThreadPool`, where each connection has a thread
+/// of its own, connections are only closed by their clients.
+#[derive(Debug)]
+pub struct KvsServer<P = NaiveThreadPool> {
+    store: KvStore,
+    protocol: Protocol,
+    /// None until set, `thread_pool` then picking `DEFAULT_IDLE_TIMEOUT`.
+    idle_timeout: Option<Option<Duration>>,
+    pool: P,
+    scan_cursors: Arc<Mutex<ScanCursors>>,
+}
+
+impl KvsServer {
+    /// Creates a server for `store`, speaking `Protocol::Kvs`, which handles each connection on a thread of its own
+    /// and never closes idle connections.
+    pub fn new(store: KvStore) -> Self {
+        Self {
+            store,
+            protocol: Protocol::Kvs,
+            idle_timeout: None,
+            pool: NaiveThreadPool,
+            scan_cursors: Arc::default(),
+        }
+    }
+}
+
+impl<P: ThreadPool> KvsServer<P> {
+    /// Sets the thread pool connections are handled on. Since a connection keeps its thread until it is closed,
+    /// connections idle for `DEFAULT_IDLE_TIMEOUT` are then closed, unless an idle timeout was set.
+    pub fn thread_pool<Q: ThreadPool>(self, pool: Q) -> KvsServer<Q> {
+        KvsServer {
+            store: self.store,
+            protocol: self.protocol,
+            idle_timeout: self.idle_timeout.or(Some(Some(DEFAULT_IDLE_TIMEOUT))),
+            pool,
+            scan_cursors: self.scan_cursors,
+        }
+    }
+
+    /// Sets the protocol spoken by the server.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets how long a connection can wait before sending its next request, after which it is closed.
+    /// With None, connections are only closed by their clients.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Listens on `addr`, serving connections until accepting one fails.
+    pub fn run(self, addr: impl ToSocketAddrs) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// Serves the connections accepted by `listener`, until accepting one fails.
+    ///
+    /// # Errors
+    ///
+    /// It also fails if the idle timeout is zero.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        let idle_timeout = self.idle_timeout.flatten();
+        if idle_timeout == Some(Duration::ZERO) {
+            bail!("The idle timeout can't be zero");
+        }
+        for stream in listener.incoming() {
+            let stream = stream?;
+            // also bounds how long a request can take to arrive once started
+            stream.set_read_timeout(idle_timeout)?;
+            let store = self.store.clone();
+            let protocol = self.protocol;
+            let scan_cursors = Arc::clone(&self.scan_cursors);
+            self.pool.spawn(move || {
+                let result = match protocol {
+                    Protocol::Kvs => serve_connection(&store, stream),
+                    Protocol::Resp => resp::serve_connection(&store, &scan_cursors, stream),
+                    Protocol::Http => http::serve_connection(&store, stream),
+                };
+                if let Err(err) = result {
+                    eprintln!("error: {:?}", err);
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn serve_connection(store: &KvStore, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while wait_for_request(&mut reader)? {
+        let request = match read_message(&mut reader)? {
+            Some(request) => request,
+            None => break,
+        };
+        let response = match handle(store, request) {
+            Ok(value) => Response::Ok(value),
+            Err(err) => Response::Err(format!("{:#}", err)),
+        };
+        write_message(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+/// Waits for the next request of a connection, returning false if the client closed the connection,
+/// or sent nothing before the read timeout of the connection.
+pub(crate) fn wait_for_request<R>(reader: &mut R) -> Result<bool>
+where
+    R: BufRead,
+{
+    match reader.fill_buf() {
+        Ok(buf) => Ok(!buf.is_empty()),
+        // the read timed out (which is WouldBlock on Unix)
+        Err(err) => match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Ok(false),
+            _ => Err(err.into()),
+        },
+    }
+}
+
+fn handle(store: &KvStore, request: Request) -> Result<Option<Vec<u8>>> {
+    match request {
+        Request::Get { key } => store.get_bytes(&key),
+        Request::Write(Command::Set { key, value }) => store.set_bytes(&key, &value).map(|_| None),
+        Request::Write(Command::Remove { key }) => store.remove_bytes(&key).map(|_| None),
+        Request::Write(command) => bail!("Unsupported command: {:?}", command),
+    }
+}
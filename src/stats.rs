@@ -0,0 +1,19 @@
+use super::SyncPolicy;
+
+/// Statistics about a `KvStore`, as returned by `KvStore::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Number of live keys in the namespace of the `KvStore` handle.
+    pub keys: usize,
+    /// Estimate of the bytes of stale data in the logs, eligible for compaction.
+    pub wasted_bytes: usize,
+    /// Whether a compaction is currently running in the background.
+    pub compacting: bool,
+    /// The durability mode writes are made with.
+    pub sync_policy: SyncPolicy,
+    /// Number of syncs to disk made since the store was opened.
+    pub syncs: u64,
+    /// Ratio of the size of the records written since the store was opened to their size on disk,
+    /// which is 1.0 without compression.
+    pub compression_ratio: f64,
+}
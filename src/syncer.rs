@@ -0,0 +1,155 @@
+use super::{Result, SyncPolicy};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+
+/// Syncer decides when writes flushed to the current log file are synced to disk, according to the `SyncPolicy`.
+///
+/// Every write flushed by the writer gets a sequence number from `record_write`. Syncing is done by a single
+/// "leader" at a time, which syncs everything written so far; with `SyncPolicy::GroupCommit`, concurrent writers
+/// waiting on the same sync are all acknowledged by it, so they share a single `sync_data` call.
+#[derive(Debug)]
+pub struct Syncer {
+    policy: SyncPolicy,
+    state: Mutex<SyncState>,
+    // notified every time a leader is done syncing
+    synced: Condvar,
+    // total number of syncs to disk
+    syncs: AtomicU64,
+    // dropping the Syncer stops the periodic sync thread (if any)
+    _stop_periodic: Sender<()>,
+}
+
+#[derive(Debug)]
+struct SyncState {
+    // handle to the current log file
+    file: Option<Arc<fs::File>>,
+    // sequence number of the latest write flushed to the file
+    written: u64,
+    // sequence number up to which writes are synced to disk
+    synced: u64,
+    // whether a leader is currently syncing
+    syncing: bool,
+}
+
+impl Syncer {
+    /// Creates a Syncer for the given policy. With `SyncPolicy::Periodic`, this starts a thread syncing in the background.
+    pub fn start(policy: SyncPolicy, file: Option<fs::File>) -> Result<Arc<Self>> {
+        let (stop_periodic, stopped) = mpsc::channel();
+        let syncer = Arc::new(Self {
+            policy,
+            state: Mutex::new(SyncState {
+                file: file.map(Arc::new),
+                written: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+            syncs: AtomicU64::new(0),
+            _stop_periodic: stop_periodic,
+        });
+        if let SyncPolicy::Periodic(interval) = policy {
+            let weak_syncer = Arc::downgrade(&syncer);
+            thread::Builder::new()
+                .name("kvs-periodic-sync".to_owned())
+                .spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                        match Weak::upgrade(&weak_syncer) {
+                            // a failed sync is simply retried at the next tick
+                            Some(syncer) => drop(syncer.sync_all()),
+                            None => break,
+                        }
+                    }
+                })?;
+        }
+        Ok(syncer)
+    }
+
+    /// The policy this Syncer applies.
+    pub fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    /// Total number of syncs to disk so far.
+    pub fn syncs(&self) -> u64 {
+        self.syncs.load(Ordering::SeqCst)
+    }
+
+    /// Records that a write was flushed to the current log file, returning its sequence number.
+    /// With `SyncPolicy::Always`, the write is synced right away.
+    ///
+    /// This must be called by the writer, while it still holds the writer lock.
+    pub fn record_write(&self) -> Result<u64> {
+        let seq = {
+            let mut state = self.state.lock().unwrap();
+            state.written += 1;
+            state.written
+        };
+        if self.policy == SyncPolicy::Always {
+            self.sync_up_to(seq)?;
+        }
+        Ok(seq)
+    }
+
+    /// Waits for the write with sequence number `seq` to be durable, as far as the policy requires.
+    ///
+    /// This must be called after releasing the writer lock, so that other writers can join the same group commit.
+    pub fn wait_for(&self, seq: u64) -> Result<()> {
+        match self.policy {
+            SyncPolicy::GroupCommit => self.sync_up_to(seq),
+            _ => Ok(()),
+        }
+    }
+
+    /// Switches to a new log file. Everything written to the previous file is synced first (unless the policy
+    /// is `SyncPolicy::Never`).
+    ///
+    /// This must be called by the writer, while it still holds the writer lock.
+    pub fn rotate(&self, file: fs::File) -> Result<()> {
+        if self.policy != SyncPolicy::Never {
+            self.sync_all()?;
+        }
+        self.state.lock().unwrap().file = Some(Arc::new(file));
+        Ok(())
+    }
+
+    /// Syncs everything written so far.
+    pub fn sync_all(&self) -> Result<()> {
+        let written = self.state.lock().unwrap().written;
+        self.sync_up_to(written)
+    }
+
+    /// Makes sure writes up to `seq` are synced, either by syncing as the leader, or by waiting for the current leader.
+    fn sync_up_to(&self, seq: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= seq {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+            let file = match &state.file {
+                Some(file) => Arc::clone(file),
+                None => return Ok(()),
+            };
+            let target = state.written;
+            state.syncing = true;
+            drop(state);
+
+            let result = file.sync_data();
+            self.syncs.fetch_add(1, Ordering::SeqCst);
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() && state.synced < target {
+                state.synced = target;
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+}
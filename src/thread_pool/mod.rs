@@ -0,0 +1,22 @@
+use super::Result;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+/// Trait for a pool of threads running jobs, such as the connections of a `KvsServer`.
+pub trait ThreadPool {
+    /// Creates a pool of `threads` threads. This fails if the threads can't be started.
+    fn new(threads: usize) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// Runs `job` on one of the threads of the pool. A job which panics doesn't take the pool down with it.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
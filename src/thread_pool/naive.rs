@@ -0,0 +1,21 @@
+use super::ThreadPool;
+use crate::Result;
+use std::thread;
+
+/// NaiveThreadPool isn't really a pool: each job runs on a thread of its own, however many jobs are running.
+#[derive(Debug)]
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    /// The number of threads is ignored, since a thread is started for each job.
+    fn new(_threads: usize) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
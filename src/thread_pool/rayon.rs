@@ -0,0 +1,33 @@
+use super::ThreadPool;
+use crate::Result;
+use anyhow::Context;
+
+/// RayonThreadPool runs jobs on a rayon thread pool of a fixed number of threads.
+///
+/// rayon is designed for CPU-bound jobs: a job blocking on I/O keeps its thread from the other jobs
+/// until it returns, as with `SharedQueueThreadPool`.
+#[derive(Debug)]
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|id| format!("rayon-{}", id))
+            // without a handler, rayon aborts the process when a job panics;
+            // the panic is already reported by the panic hook
+            .panic_handler(|_| {})
+            .build()
+            .context("Starting thread pool")?;
+        Ok(Self { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job)
+    }
+}
@@ -0,0 +1,51 @@
+use super::ThreadPool;
+use crate::Result;
+use anyhow::{bail, Context};
+use crossbeam_channel::{Receiver, Sender};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// SharedQueueThreadPool runs jobs on a fixed number of threads, which take them from a shared queue.
+///
+/// A job which panics is caught, so its thread goes on with the next job. The threads exit once the pool
+/// is dropped and the queue is drained.
+#[derive(Debug)]
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        if threads == 0 {
+            bail!("The thread pool needs at least one thread");
+        }
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        for id in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("shared-queue-{}", id))
+                .spawn(move || run_jobs(receiver))
+                .context("Starting thread pool")?;
+        }
+        Ok(Self { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // the threads only stop once the sender is dropped, so sending can't fail
+        self.sender
+            .send(Box::new(job))
+            .expect("The threads of the pool have stopped");
+    }
+}
+
+fn run_jobs(receiver: Receiver<Job>) {
+    for job in receiver {
+        // the panic is already reported by the panic hook
+        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+    }
+}
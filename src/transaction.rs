@@ -0,0 +1,88 @@
+use super::{Result, Snapshot};
+use anyhow::bail;
+use std::collections::BTreeMap;
+
+/// A read-modify-write transaction over any number of keys, started by `KvStore::transaction`.
+///
+/// Reads see the store as it was when the transaction started (plus the transaction's own writes),
+/// and writes are buffered until `commit`. Commit is optimistic: it fails if any key read by the
+/// transaction was changed by another writer in the meantime, in which case nothing is written and
+/// the transaction can simply be retried. Dropping a transaction without committing it discards its writes.
+///
+/// On disk, the writes of a transaction are followed by a commit marker, so that a transaction
+/// interrupted by a crash is ignored when the log is replayed.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// # let store = KvStore::open(std::env::current_dir()?)?;
+/// let mut txn = store.transaction();
+/// let from = txn.get("from")?.unwrap_or_default();
+/// txn.set("to", from);
+/// txn.remove("from")?;
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Transaction {
+    snapshot: Snapshot,
+    // keys read from the snapshot, with the sequence number of the entry they had (None if absent)
+    reads: BTreeMap<Vec<u8>, Option<u64>>,
+    // latest value written to each key by the transaction (None if removed)
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(snapshot: Snapshot) -> Self {
+        Self {
+            snapshot,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Get Some(value) as seen by the transaction, searching by the bytes of `key`.
+    /// If the `key` is not present, None will be returned.
+    pub fn get(&mut self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        let (value, seq) = self.snapshot.get_versioned(key)?;
+        self.reads.insert(key.to_vec(), seq);
+        Ok(value)
+    }
+
+    /// Set a `value` for `key` when the transaction commits.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) {
+        self.writes.insert(key.into(), Some(value.into()));
+    }
+
+    /// Removes `key` when the transaction commits. This will throw an error if the `key` does not exist
+    /// as seen by the transaction.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        if self.get(&key)?.is_none() {
+            bail!("Key not found");
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Writes all the changes of the transaction atomically.
+    ///
+    /// # Errors
+    ///
+    /// On top of I/O errors, this fails if a key read by the transaction was changed since it started.
+    pub fn commit(self) -> Result<()> {
+        if self.writes.is_empty() {
+            // reads all came from the same snapshot, so they are consistent with each other
+            return Ok(());
+        }
+        self.snapshot.store().commit_transaction(
+            self.reads.into_iter().collect(),
+            self.writes.into_iter().collect(),
+        )
+    }
+}
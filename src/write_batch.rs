@@ -0,0 +1,58 @@
+use super::command::Command;
+
+/// A group of sets and removes, applied atomically by `KvStore::write`.
+///
+/// The whole batch is written to the log as a single record, so after a crash either all of it
+/// or none of it is replayed. Commands are applied in the order they were added.
+///
+/// ```rust
+/// # use kvs::{KvStore, Result, WriteBatch};
+/// # fn try_main() -> Result<()> {
+/// # let store = KvStore::open(std::env::current_dir()?)?;
+/// let mut batch = WriteBatch::new();
+/// batch.set("from", "90").set("to", "110");
+/// store.write(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) commands: Vec<Command>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a set of `key` to `value` to the batch.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.commands.push(Command::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Adds a removal of `key` to the batch. Writing the batch fails if `key` doesn't exist at that point.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.commands.push(Command::Remove { key: key.into() });
+        self
+    }
+
+    /// Number of commands in the batch.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Whether the batch has no commands.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+
+    /// Removes all commands from the batch, so that it can be reused.
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+}
@@ -0,0 +1,653 @@
+use assert_cmd::prelude::*;
+use kvs::{
+    KvStore, KvsClient, KvsServer, Protocol, RayonThreadPool, Result, SharedQueueThreadPool,
+    ThreadPool,
+};
+use predicates::str::{contains, is_empty};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Serves the store in `temp_dir` on a thread, returning the address it listens on.
+fn start_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    start_server_with_protocol(temp_dir, Protocol::Kvs)
+}
+
+fn start_server_with_protocol(temp_dir: &TempDir, protocol: Protocol) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).protocol(protocol).serve(listener));
+    Ok(addr)
+}
+
+fn start_server_with_pool(
+    temp_dir: &TempDir,
+    pool: impl ThreadPool + Send + 'static,
+) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || KvsServer::new(store).thread_pool(pool).serve(listener));
+    Ok(addr)
+}
+
+// A minimal Redis client, which returns replies as they were sent.
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: SocketAddr) -> Result<Self> {
+        let writer = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    // Sends a command as an array of bulk strings, like Redis clients do.
+    fn command(&mut self, args: &[&str]) -> Result<String> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.send(&request)
+    }
+
+    fn send(&mut self, request: &str) -> Result<String> {
+        self.writer.write_all(request.as_bytes())?;
+        self.read_reply()
+    }
+
+    fn read_reply(&mut self) -> Result<String> {
+        let mut reply = String::new();
+        self.reader.read_line(&mut reply)?;
+        let len: i64 = reply[1..reply.len() - 2].parse().unwrap_or(0);
+        match reply.as_bytes()[0] {
+            b'$' if len >= 0 => {
+                let mut bulk = vec![0; len as usize + 2];
+                self.reader.read_exact(&mut bulk)?;
+                reply.push_str(&String::from_utf8(bulk)?);
+            }
+            b'*' => {
+                for _ in 0..len {
+                    reply.push_str(&self.read_reply()?);
+                }
+            }
+            _ => {}
+        }
+        Ok(reply)
+    }
+}
+
+// Sends an HTTP request on its own connection, returning the status and body of the response.
+fn http(addr: SocketAddr, method: &str, target: &str, body: &str) -> Result<(u16, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        target,
+        body.len(),
+        body
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.split(' ').nth(1).unwrap().parse()?;
+    Ok((status, body.to_owned()))
+}
+
+// A `kvs-server` process, killed when dropped.
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// Runs `kvs-server` with `args` on a free port, returning the process and its address once it accepts connections.
+fn spawn_server_process(temp_dir: &TempDir, args: &[&str]) -> (ServerProcess, SocketAddr) {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = ServerProcess(
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr.to_string()])
+            .args(args)
+            .current_dir(temp_dir)
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if TcpStream::connect(addr).is_ok() {
+            return (server, addr);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("kvs-server didn't start listening on {}", addr);
+}
+
+#[test]
+fn client_get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    // errors of the store are sent back to the client, which can keep using the connection
+    let err = client.remove("key1".to_owned()).unwrap_err();
+    assert_eq!(err.to_string(), "Key not found");
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // several clients share the same store
+    let mut other_client = KvsClient::connect(addr)?;
+    assert_eq!(
+        other_client.get("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// Writes from 8 clients at a time, then reads everything back.
+fn check_concurrent_clients(addr: SocketAddr) -> Result<()> {
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr)?;
+                for j in 0..50 {
+                    client.set(format!("key{}-{}", i, j), format!("value{}", j))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    for i in 0..8 {
+        for j in 0..50 {
+            assert_eq!(
+                client.get(format!("key{}-{}", i, j))?,
+                Some(format!("value{}", j))
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_clients(start_server(&temp_dir)?)
+}
+
+// With fewer threads than clients, connections wait for a thread to be free.
+#[test]
+fn concurrent_clients_shared_queue_thread_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = SharedQueueThreadPool::new(4)?;
+    check_concurrent_clients(start_server_with_pool(&temp_dir, pool)?)
+}
+
+#[test]
+fn concurrent_clients_rayon_thread_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pool = RayonThreadPool::new(4)?;
+    check_concurrent_clients(start_server_with_pool(&temp_dir, pool)?)
+}
+
+// Idle connections are closed after the idle timeout, so they can't keep the threads of the pool
+// from the connections waiting for one.
+#[test]
+fn idle_connections_closed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    // an idle timeout set before the pool is kept, rather than replaced by the default of the pool
+    let server = KvsServer::new(store)
+        .idle_timeout(Some(Duration::from_millis(200)))
+        .thread_pool(SharedQueueThreadPool::new(1)?);
+    thread::spawn(move || server.serve(listener));
+
+    let mut idle = TcpStream::connect(addr)?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = KvsClient::connect(addr).and_then(|mut client| {
+            client.set("key1".to_owned(), "value1".to_owned())?;
+            client.get("key1".to_owned())
+        });
+        let _ = sender.send(result);
+    });
+    let value = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("the idle connection kept the only thread of the pool")?;
+    assert_eq!(value, Some("value1".to_owned()));
+
+    // the server closed the idle connection
+    idle.set_read_timeout(Some(Duration::from_secs(5)))?;
+    assert_eq!(idle.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+#[test]
+fn cli_client_server() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (server, addr) = spawn_server_process(&temp_dir, &[]);
+    let addr = addr.to_string();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", &addr])
+        .assert()
+        .success();
+
+    drop(server);
+
+    // the server wrote to the store in its working directory
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    assert_eq!(store.get_bytes(b"key1").unwrap(), None);
+    assert_eq!(store.get_bytes(b"key2").unwrap(), Some(b"value2".to_vec()));
+}
+
+#[test]
+fn cli_server_thread_pool() {
+    for pool in &["shared-queue", "rayon"] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let (_server, addr) =
+            spawn_server_process(&temp_dir, &["--thread-pool", pool, "--threads", "2"]);
+        let addr = addr.to_string();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", &addr])
+            .assert()
+            .success()
+            .stdout(is_empty());
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", &addr])
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+}
+
+#[test]
+fn cli_client_no_server() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .failure()
+        .stderr(contains("Connecting to server"));
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_protocol(&temp_dir, Protocol::Resp)?;
+    let mut client = RespClient::connect(addr)?;
+
+    assert_eq!(client.command(&["PING"])?, "+PONG\r\n");
+    assert_eq!(client.command(&["ping", "hello"])?, "$5\r\nhello\r\n");
+    // inline commands, as typed in telnet
+    assert_eq!(client.send("PING\r\n")?, "+PONG\r\n");
+
+    assert_eq!(client.command(&["SET", "key1", "value1"])?, "+OK\r\n");
+    assert_eq!(client.command(&["GET", "key1"])?, "$6\r\nvalue1\r\n");
+    assert_eq!(client.command(&["GET", "key2"])?, "$-1\r\n");
+    assert_eq!(
+        client.command(&["EXISTS", "key1", "key2", "key1"])?,
+        ":2\r\n"
+    );
+    assert_eq!(client.command(&["DEL", "key1", "key2"])?, ":1\r\n");
+    assert_eq!(client.command(&["GET", "key1"])?, "$-1\r\n");
+
+    assert_eq!(client.command(&["INCR", "counter"])?, ":1\r\n");
+    assert_eq!(client.command(&["INCR", "counter"])?, ":2\r\n");
+    assert_eq!(client.command(&["GET", "counter"])?, "$1\r\n2\r\n");
+    client.command(&["SET", "key1", "value1"])?;
+    assert_eq!(
+        client.command(&["INCR", "key1"])?,
+        "-ERR value is not an integer or out of range\r\n"
+    );
+
+    assert_eq!(
+        client.command(&["GET"])?,
+        "-ERR wrong number of arguments for 'get' command\r\n"
+    );
+    assert_eq!(
+        client.command(&["FLUSHALL"])?,
+        "-ERR unknown command 'flushall'\r\n"
+    );
+    // the connection is still usable after errors
+    assert_eq!(client.command(&["PING"])?, "+PONG\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_protocol(&temp_dir, Protocol::Resp)?;
+    let mut client = RespClient::connect(addr)?;
+
+    assert_eq!(
+        client.command(&["SET", "key1", "value1", "PX", "100"])?,
+        "+OK\r\n"
+    );
+    assert_eq!(client.command(&["SET", "key2", "value2"])?, "+OK\r\n");
+    assert_eq!(client.command(&["EXPIRE", "key2", "100"])?, ":1\r\n");
+    assert_eq!(client.command(&["EXPIRE", "key3", "100"])?, ":0\r\n");
+    assert_eq!(client.command(&["SET", "key3", "value3"])?, "+OK\r\n");
+    // a ttl which isn't positive removes the key right away
+    assert_eq!(client.command(&["EXPIRE", "key3", "0"])?, ":1\r\n");
+    assert_eq!(client.command(&["EXISTS", "key3"])?, ":0\r\n");
+    assert_eq!(
+        client.command(&["SET", "key3", "value3", "EX", "-1"])?,
+        "-ERR invalid expire time in 'set' command\r\n"
+    );
+
+    // INCR keeps the time-to-live of the counter
+    assert_eq!(
+        client.command(&["SET", "counter", "1", "PX", "100"])?,
+        "+OK\r\n"
+    );
+    assert_eq!(client.command(&["INCR", "counter"])?, ":2\r\n");
+
+    assert_eq!(client.command(&["GET", "key1"])?, "$6\r\nvalue1\r\n");
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.command(&["GET", "key1"])?, "$-1\r\n");
+    assert_eq!(client.command(&["GET", "key2"])?, "$6\r\nvalue2\r\n");
+    assert_eq!(client.command(&["GET", "counter"])?, "$-1\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp_keys_and_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_protocol(&temp_dir, Protocol::Resp)?;
+    let mut client = RespClient::connect(addr)?;
+
+    for key in &[
+        "user:1", "user:2", "user:10", "order:1", "hello", "hallo", "hxllo",
+    ] {
+        client.command(&["SET", key, "value"])?;
+    }
+    assert_eq!(
+        client.command(&["KEYS", "user:*"])?,
+        "*3\r\n$6\r\nuser:1\r\n$7\r\nuser:10\r\n$6\r\nuser:2\r\n"
+    );
+    assert_eq!(
+        client.command(&["KEYS", "h[ae]llo"])?,
+        "*2\r\n$5\r\nhallo\r\n$5\r\nhello\r\n"
+    );
+    assert_eq!(
+        client.command(&["KEYS", "h[^e]llo"])?,
+        "*2\r\n$5\r\nhallo\r\n$5\r\nhxllo\r\n"
+    );
+    assert_eq!(
+        client.command(&["KEYS", "*:?"])?,
+        "*3\r\n$7\r\norder:1\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n"
+    );
+    assert_eq!(client.command(&["KEYS", "nothing*"])?, "*0\r\n");
+    // patterns with many stars don't backtrack exponentially
+    let long_key = "a".repeat(1000);
+    client.command(&["SET", &long_key, "value"])?;
+    assert_eq!(client.command(&["KEYS", "*a*a*a*a*a*a*a*a*a*b"])?, "*0\r\n");
+    assert_eq!(client.command(&["DEL", &long_key])?, ":1\r\n");
+
+    // scanning 3 keys at a time returns every key once, then cursor 0
+    assert_eq!(
+        client.command(&["SCAN", "0", "COUNT", "3"])?,
+        "*2\r\n$1\r\n1\r\n*3\r\n$5\r\nhallo\r\n$5\r\nhello\r\n$5\r\nhxllo\r\n"
+    );
+    // the cursor continues after the last key returned, so removing keys doesn't make it skip others
+    assert_eq!(client.command(&["DEL", "hello", "order:1"])?, ":2\r\n");
+    // cursors belong to the server, so a scan can continue on another connection, as pooled clients do
+    let mut other_client = RespClient::connect(addr)?;
+    assert_eq!(
+        other_client.command(&["SCAN", "1", "COUNT", "3"])?,
+        "*2\r\n$1\r\n0\r\n*3\r\n$6\r\nuser:1\r\n$7\r\nuser:10\r\n$6\r\nuser:2\r\n"
+    );
+    // MATCH filters the keys examined
+    assert_eq!(
+        client.command(&["SCAN", "0", "MATCH", "user:*"])?,
+        "*2\r\n$1\r\n0\r\n*3\r\n$6\r\nuser:1\r\n$7\r\nuser:10\r\n$6\r\nuser:2\r\n"
+    );
+    assert_eq!(client.command(&["SCAN", "x"])?, "-ERR invalid cursor\r\n");
+    assert_eq!(client.command(&["SCAN", "99"])?, "-ERR invalid cursor\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp_protocol_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_protocol(&temp_dir, Protocol::Resp)?;
+    let mut client = RespClient::connect(addr)?;
+
+    assert_eq!(
+        client.send("*1\r\n+PING\r\n")?,
+        "-ERR Protocol error: expected '$', got '+PING'\r\n"
+    );
+    // the server closes the connection
+    let mut rest = Vec::new();
+    client.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
+
+#[test]
+fn cli_server_resp() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (_server, addr) = spawn_server_process(&temp_dir, &["--protocol", "resp"]);
+
+    let mut client = RespClient::connect(addr)?;
+    assert_eq!(client.command(&["SET", "key1", "value1"])?, "+OK\r\n");
+    assert_eq!(client.command(&["GET", "key1"])?, "$6\r\nvalue1\r\n");
+    Ok(())
+}
+
+#[test]
+fn http_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_protocol(&temp_dir, Protocol::Http)?;
+
+    assert_eq!(
+        http(addr, "PUT", "/keys/key1", "value1")?,
+        (204, "".to_owned())
+    );
+    assert_eq!(
+        http(addr, "GET", "/keys/key1", "")?,
+        (200, "value1".to_owned())
+    );
+    // keys are percent-decoded
+    assert_eq!(http(addr, "PUT", "/keys/a%20key%2F1", "value2")?.0, 204);
+    assert_eq!(
+        http(addr, "GET", "/keys/a%20key%2f1", "")?,
+        (200, "value2".to_owned())
+    );
+
+    assert_eq!(http(addr, "DELETE", "/keys/key1", "")?.0, 204);
+    assert_eq!(
+        http(addr, "GET", "/keys/key1", "")?,
+        (404, r#"{"error":"Key not found"}"#.to_owned())
+    );
+    assert_eq!(
+        http(addr, "DELETE", "/keys/key1", "")?,
+        (404, r#"{"error":"Key not found"}"#.to_owned())
+    );
+
+    assert_eq!(http(addr, "PUT", "/keys/session?ttl=1", "token")?.0, 204);
+    assert_eq!(
+        http(addr, "GET", "/keys/session", "")?,
+        (200, "token".to_owned())
+    );
+    assert_eq!(http(addr, "PUT", "/keys/session?ttl=soon", "token")?.0, 400);
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(http(addr, "GET", "/keys/session", "")?.0, 404);
+
+    assert_eq!(http(addr, "POST", "/keys/key1", "value1")?.0, 405);
+    assert_eq!(http(addr, "GET", "/nothing", "")?.0, 404);
+    Ok(())
+}
+
+#[test]
+fn http_list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_protocol(&temp_dir, Protocol::Http)?;
+    for key in &["user:1", "user:2", "user:3", "order:1", "zebra"] {
+        http(addr, "PUT", &format!("/keys/{}", key), "value")?;
+    }
+
+    assert_eq!(
+        http(addr, "GET", "/keys", "")?,
+        (
+            200,
+            r#"{"keys":["order:1","user:1","user:2","user:3","zebra"],"next":null}"#.to_owned()
+        )
+    );
+    // pages of 2 keys, each continuing after the `next` key of the previous one
+    assert_eq!(
+        http(addr, "GET", "/keys?prefix=user%3A&limit=2", "")?,
+        (
+            200,
+            r#"{"keys":["user:1","user:2"],"next":"user:2"}"#.to_owned()
+        )
+    );
+    assert_eq!(
+        http(addr, "GET", "/keys?prefix=user:&limit=2&after=user:2", "")?,
+        (200, r#"{"keys":["user:3"],"next":null}"#.to_owned())
+    );
+    assert_eq!(
+        http(addr, "GET", "/keys?prefix=nothing", "")?,
+        (200, r#"{"keys":[],"next":null}"#.to_owned())
+    );
+    assert_eq!(http(addr, "GET", "/keys?limit=0", "")?.0, 400);
+
+    // keys which aren't UTF-8 are listed percent-encoded, so that paging through them makes progress
+    http(addr, "PUT", "/keys/%FF1", "value")?;
+    http(addr, "PUT", "/keys/%FF2", "value")?;
+    assert_eq!(
+        http(addr, "GET", "/keys?prefix=%FF&limit=1", "")?,
+        (200, r#"{"keys":["%FF1"],"next":"%FF1"}"#.to_owned())
+    );
+    assert_eq!(
+        http(addr, "GET", "/keys?prefix=%FF&limit=1&after=%FF1", "")?,
+        (200, r#"{"keys":["%FF2"],"next":null}"#.to_owned())
+    );
+    assert_eq!(
+        http(addr, "GET", "/keys/%FF2", "")?,
+        (200, "value".to_owned())
+    );
+    Ok(())
+}
+
+#[test]
+fn http_health_and_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_protocol(&temp_dir, Protocol::Http)?;
+    http(addr, "PUT", "/keys/key1", "value1")?;
+
+    assert_eq!(
+        http(addr, "GET", "/health", "")?,
+        (200, r#"{"status":"ok"}"#.to_owned())
+    );
+    let (status, stats) = http(addr, "GET", "/stats", "")?;
+    assert_eq!(status, 200);
+    assert!(stats.contains(r#""keys":1"#), "{}", stats);
+    assert!(stats.contains(r#""sync_policy":"Never""#), "{}", stats);
+    Ok(())
+}
+
+#[test]
+fn http_keep_alive() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server_with_protocol(&temp_dir, Protocol::Http)?;
+
+    // HTTP/1.1 connections are kept open between requests
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"PUT /keys/key1 HTTP/1.1\r\nContent-Length: 6\r\n\r\nvalue1")?;
+    stream.write_all(b"GET /keys/key1 HTTP/1.1\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert_eq!(
+        response,
+        "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n\
+         HTTP/1.1 200 OK\r\nContent-Length: 6\r\nContent-Type: application/octet-stream\r\n\
+         Connection: close\r\n\r\nvalue1"
+    );
+    Ok(())
+}
+
+#[test]
+fn cli_server_no_threads() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:0",
+            "--thread-pool",
+            "rayon",
+            "--threads",
+            "0",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("at least one thread"));
+}
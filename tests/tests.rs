@@ -1,10 +1,14 @@
-use walkdir::WalkDir;
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::{Compression, KvStore, KvsEngine, Result, SyncPolicy, WriteBatch};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use serde::Serialize;
 use std::process::Command;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
+use walkdir::WalkDir;
 
 // `kvs` with no args should exit with a non-zero code.
 #[test]
@@ -17,7 +21,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -28,7 +32,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -41,7 +45,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -54,7 +58,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -65,14 +69,14 @@ fn cli_set() {
 fn cli_get_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +84,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -94,13 +98,13 @@ fn cli_get_stored() -> Result<()> {
 fn cli_rm_stored() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     drop(store);
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,7 +112,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -121,13 +125,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +140,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +161,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +176,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -181,7 +185,7 @@ fn cli_invalid_subcommand() {
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -191,7 +195,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -202,7 +206,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -211,7 +215,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -223,14 +227,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -239,7 +243,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -247,7 +251,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -259,7 +263,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -267,6 +271,11 @@ fn compaction() -> Result<()> {
             .map(|res| {
                 res.and_then(|entry| entry.metadata())
                     .map(|metadata| metadata.len())
+                    .or_else(|err| match err.io_error() {
+                        // e.g. MANIFEST.tmp, renamed while the directory is walked
+                        Some(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(0),
+                        _ => Err(err),
+                    })
             })
             .sum();
         len.expect("fail to get directory size")
@@ -289,7 +298,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content.
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -299,3 +308,1812 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should be usable through the `KvsEngine` trait object.
+#[test]
+fn engine_trait_object() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine: Box<dyn KvsEngine> = Box::new(KvStore::open(temp_dir.path())?);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(engine.remove("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// `KvStore` handles should be shareable between threads.
+#[test]
+fn store_is_clone_send_sync() {
+    fn assert_clone_send_sync<T: Clone + Send + Sync + 'static>() {}
+    assert_clone_send_sync::<KvStore>();
+}
+
+// Concurrent writes from many handles should all be persisted.
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let barrier = Arc::new(Barrier::new(8));
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                for i in 0..100 {
+                    store
+                        .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Readers on other threads should keep seeing valid values while a writer overwrites keys,
+// including across compactions.
+#[test]
+fn concurrent_get_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..20 {
+                    for key_id in 0..100 {
+                        let value = store.get(format!("key{}", key_id)).unwrap();
+                        assert!(value.unwrap().parse::<u32>().is_ok());
+                    }
+                }
+            })
+        })
+        .collect();
+    let value = "x".repeat(1000);
+    for iter in 0..30 {
+        for key_id in 0..100 {
+            store.set(format!("pad{}", key_id), value.clone())?;
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("29".to_owned()));
+    }
+
+    Ok(())
+}
+
+fn log_files(dir: &std::path::Path, extension: &str) -> Vec<String> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some(extension.as_ref()))
+        .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+        .collect()
+}
+
+// Compaction runs in the background, and can be waited for.
+#[test]
+fn wait_for_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let value = "x".repeat(1000);
+    for iter in 0..3 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    store.wait_for_compaction()?;
+
+    assert!(log_files(temp_dir.path(), "tmp").is_empty());
+    assert!(log_files(temp_dir.path(), "log").len() <= 2);
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("2{}", value))
+        );
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("2{}", value))
+        );
+    }
+
+    Ok(())
+}
+
+// A cancelled compaction should leave no temporary files behind, and lose no data.
+#[test]
+fn cancel_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let value = "x".repeat(1000);
+    for iter in 0..2 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    store.cancel_compaction()?;
+    assert!(log_files(temp_dir.path(), "tmp").is_empty());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("1{}", value))
+        );
+    }
+
+    Ok(())
+}
+
+// A background compaction which fails should not fail the writes after it, and its error should be reported
+// by `wait_for_compaction`, with the wasted bytes left for the next compaction.
+#[test]
+fn failed_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    // thanks to the hint file, the store opens without reading the corrupted record, but compaction reads it
+    let hints = log_files(temp_dir.path(), "hint");
+    let compacted_log = temp_dir.path().join(hints[0].replace(".hint", ".log"));
+    let mut contents = std::fs::read(&compacted_log)?;
+    let last = contents.len() - 1;
+    contents[last] ^= 0xff;
+    std::fs::write(&compacted_log, &contents)?;
+
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for iter in 0..20 {
+        store.set("pad".to_owned(), format!("{}{}", iter, "x".repeat(100)))?;
+    }
+    assert!(store.wait_for_compaction().is_err());
+    assert!(store.stats().wasted_bytes >= 1024);
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    Ok(())
+}
+
+// Keys removed and namespaces dropped while compactions are starting and running should stay removed
+// once the store is opened again, and the store should open at all.
+#[test]
+fn writes_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(2000)
+        .open(temp_dir.path())?;
+    let mut expected = std::collections::BTreeMap::new();
+    let mut namespace_id = 0;
+    let mut namespace = store.namespace("ns0")?;
+    for op in 0..2000 {
+        let key = format!("key{}", op % 40);
+        if op % 3 == 2 && expected.contains_key(&key) {
+            store.remove(key.clone())?;
+            expected.remove(&key);
+        } else {
+            store.set(key.clone(), format!("value{}", op))?;
+            expected.insert(key, format!("value{}", op));
+        }
+        namespace.set(format!("key{}", op % 10), format!("value{}", op))?;
+        // drop the namespace as soon as a compaction is running, before it gets to the namespaces
+        if store.stats().compacting {
+            store.drop_namespace(&format!("ns{}", namespace_id))?;
+            namespace_id += 1;
+            namespace = store.namespace(&format!("ns{}", namespace_id))?;
+        }
+    }
+    store.wait_for_compaction()?;
+    assert!(namespace_id > 0);
+
+    // Open from disk again and check persistent data.
+    drop((store, namespace));
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..40 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+    }
+    assert_eq!(
+        store.namespaces(),
+        vec!["default".to_owned(), format!("ns{}", namespace_id)]
+    );
+
+    Ok(())
+}
+
+// Handles waiting for a compaction all return once it has finished, and no other compaction starts meanwhile,
+// even as writes go on.
+#[test]
+fn wait_for_compaction_from_other_handles() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(2000)
+        .open(temp_dir.path())?;
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let waiters: Vec<_> = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            let done = Arc::clone(&done);
+            thread::spawn(move || -> Result<()> {
+                while !done.load(std::sync::atomic::Ordering::SeqCst) {
+                    store.wait_for_compaction()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    let mut expected = std::collections::BTreeMap::new();
+    for op in 0..2000 {
+        let key = format!("key{}", op % 40);
+        if op % 3 == 2 && expected.contains_key(&key) {
+            store.remove(key.clone())?;
+            expected.remove(&key);
+        } else {
+            store.set(key.clone(), format!("value{}", op))?;
+            expected.insert(key, format!("value{}", op));
+        }
+    }
+    done.store(true, std::sync::atomic::Ordering::SeqCst);
+    for waiter in waiters {
+        waiter.join().unwrap()?;
+    }
+    store.wait_for_compaction()?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..40 {
+        let key = format!("key{}", key_id);
+        assert_eq!(store.get(key.clone())?, expected.get(&key).cloned());
+    }
+
+    // a compaction long enough for both handles to wait for it: neither returns before it has finished
+    let value = "x".repeat(1000);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    drop(store);
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    store.set("key0".to_owned(), value)?;
+    assert!(store.stats().compacting);
+    let waiters: Vec<_> = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            let temp_dir = temp_dir.path().to_owned();
+            thread::spawn(move || -> Result<()> {
+                store.wait_for_compaction()?;
+                assert!(log_files(&temp_dir, "tmp").is_empty());
+                Ok(())
+            })
+        })
+        .collect();
+    for waiter in waiters {
+        waiter.join().unwrap()?;
+    }
+
+    Ok(())
+}
+
+// A lower compaction threshold should make compaction kick in sooner.
+#[test]
+fn builder_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .sync_policy(SyncPolicy::Always)
+        .read_buffer_size(64)
+        .write_buffer_size(64)
+        .open(temp_dir.path())?;
+
+    for iter in 0..50 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.wait_for_compaction()?;
+    assert!(!log_files(temp_dir.path(), "log").contains(&"1.log".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("49".to_owned()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn builder_create_if_missing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("store");
+
+    assert!(KvStore::builder()
+        .create_if_missing(false)
+        .open(&path)
+        .is_err());
+    assert!(!path.exists());
+
+    let store = KvStore::builder().error_if_exists(true).open(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    assert!(KvStore::builder()
+        .error_if_exists(true)
+        .open(&path)
+        .is_err());
+    let store = KvStore::builder().create_if_missing(false).open(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A read-only store should serve reads, refuse writes, and never create files.
+#[test]
+fn builder_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::builder().read_only(true).open(&missing).is_err());
+    assert!(!missing.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A write torn by a crash at the end of the log should be dropped, keeping everything before it.
+#[test]
+fn torn_write_is_truncated() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log)?.len();
+    let mut contents = std::fs::read(&log)?;
+    // half of a third record
+    contents.extend_from_within(..10);
+    std::fs::write(&log, contents)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Corruption in the middle of the log should fail the open, naming the offset of the bad record.
+#[test]
+fn corruption_is_reported() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log)?;
+    let last = contents.len() - 1;
+    contents[last] ^= 0xff;
+    std::fs::write(&log, &contents)?;
+    // a damaged last record is indistinguishable from a torn write
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let mut contents = std::fs::read(&log)?;
+    // the first record follows the 8 bytes of the file header
+    contents[18] ^= 0xff;
+    contents.extend_from_slice(&std::fs::read(&log)?);
+    std::fs::write(&log, &contents)?;
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(format!("{:?}", err).contains("offset 8"));
+
+    Ok(())
+}
+
+// Every durability mode should be reported in the stats, and sync as often as it promises.
+#[test]
+fn sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::Always,
+        SyncPolicy::GroupCommit,
+        SyncPolicy::Periodic(Duration::from_millis(10)),
+    ];
+    for &policy in policies.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .sync_policy(policy)
+            .open(temp_dir.path())?;
+        assert_eq!(store.stats().sync_policy, policy);
+
+        let handles: Vec<_> = (0..4)
+            .map(|thread_id| {
+                let store = store.clone();
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        store
+                            .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+
+        let stats = store.stats();
+        assert_eq!(stats.keys, 100);
+        match policy {
+            SyncPolicy::Never => assert_eq!(stats.syncs, 0),
+            SyncPolicy::Always => assert_eq!(stats.syncs, 100),
+            SyncPolicy::GroupCommit => assert!(stats.syncs > 0 && stats.syncs <= 100),
+            SyncPolicy::Periodic(_) => assert!(stats.syncs > 0),
+        }
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key3-24".to_owned())?, Some("value24".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Compaction should write a hint file next to the compacted log, which is used to open the store
+// without replaying the log. Without it, the log is simply replayed.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // overwrite a single key until compaction kicks in
+    for iter in 0..20 {
+        store.set("pad".to_owned(), format!("{}{}", iter, "x".repeat(100)))?;
+    }
+    store.wait_for_compaction()?;
+    drop(store);
+
+    let hints = log_files(temp_dir.path(), "hint");
+    assert_eq!(hints.len(), 1);
+    let compacted_log = temp_dir.path().join(hints[0].replace(".hint", ".log"));
+    assert!(compacted_log.exists());
+
+    // Corrupt the first record of the compacted log: the hint file means it is never replayed.
+    let mut contents = std::fs::read(&compacted_log)?;
+    contents[4] ^= 0xff;
+    std::fs::write(&compacted_log, &contents)?;
+    assert!(KvStore::open(temp_dir.path()).is_ok());
+    contents[4] ^= 0xff;
+    std::fs::write(&compacted_log, &contents)?;
+
+    std::fs::remove_file(temp_dir.path().join(&hints[0]))?;
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+
+    Ok(())
+}
+
+// Keys and values can be arbitrary bytes, and the String API works on top of the same data.
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let key = [0u8, 159, 146, 150, 255];
+    let value: Vec<u8> = (0..=255).collect();
+    store.set_bytes(&key, &value)?;
+    store.set_bytes(b"text", "value1".as_bytes())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value.clone()));
+    assert_eq!(store.get("text".to_owned())?, Some("value1".to_owned()));
+    store.set("text2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get_bytes(b"text2")?, Some(b"value2".to_vec()));
+
+    // a value which is not UTF-8 can't be read as a String
+    store.set_bytes(b"binary", &value)?;
+    assert!(store.get("binary".to_owned()).is_err());
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(&key)?, Some(value));
+    store.remove_bytes(&key)?;
+    assert_eq!(store.get_bytes(&key)?, None);
+    assert!(store.remove_bytes(&key).is_err());
+
+    Ok(())
+}
+
+fn collect_keys(scan: kvs::Scan<'_>) -> Result<Vec<String>> {
+    scan.map(|entry| Ok(String::from_utf8(entry?.0)?)).collect()
+}
+
+// Keys should be scanned in order, within the requested range.
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["b", "d", "a", "c", "e"] {
+        store.set(key.to_string(), format!("value-{}", key))?;
+    }
+    store.remove("c".to_owned())?;
+
+    assert_eq!(collect_keys(store.scan("b".."e"))?, vec!["b", "d"]);
+    assert_eq!(collect_keys(store.scan("b"..="e"))?, vec!["b", "d", "e"]);
+    assert_eq!(collect_keys(store.scan(.."b"))?, vec!["a"]);
+    assert_eq!(collect_keys(store.scan("c"..))?, vec!["d", "e"]);
+    assert_eq!(collect_keys(store.scan("e".."b"))?, Vec::<String>::new());
+    let entries: Vec<_> = store.scan("a".."b").collect::<Result<_>>()?;
+    assert_eq!(entries, vec![(b"a".to_vec(), b"value-a".to_vec())]);
+
+    // keys written after the current position of the iterator are seen
+    let mut scan = store.scan("a"..);
+    assert_eq!(scan.next().unwrap()?.0, b"a".to_vec());
+    store.set("aa".to_owned(), "value-aa".to_owned())?;
+    assert_eq!(collect_keys(scan)?, vec!["aa", "b", "d", "e"]);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        collect_keys(store.scan("a"..))?,
+        vec!["a", "aa", "b", "d", "e"]
+    );
+
+    Ok(())
+}
+
+// Prefix scans should return exactly the keys starting with the prefix.
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &[
+        "user:12",
+        "user:123:name",
+        "user:123:email",
+        "user:124:name",
+        "users",
+    ] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.set_bytes(&[0xff, 0xff], b"value")?;
+    store.set_bytes(&[0xff, 0xff, 0x01], b"value")?;
+    store.set_bytes(&[0xfe], b"value")?;
+
+    assert_eq!(
+        collect_keys(store.scan_prefix("user:123:"))?,
+        vec!["user:123:email", "user:123:name"]
+    );
+    assert_eq!(collect_keys(store.scan_prefix("user:"))?.len(), 4);
+    assert_eq!(collect_keys(store.scan_prefix("nobody"))?.len(), 0);
+    let keys: Vec<_> = store
+        .scan_prefix([0xff])
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![vec![0xff, 0xff], vec![0xff, 0xff, 0x01]]);
+    assert_eq!(store.scan_prefix("").count(), 8);
+
+    Ok(())
+}
+
+// A write batch should apply all of its commands, in order, and persist them.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .set("key3", "value3")
+        .remove("key1")
+        .set("key2", "value2b")
+        .remove("key3");
+    assert_eq!(batch.len(), 5);
+    store.write(batch)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2b".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.write(WriteBatch::new())?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2b".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// A batch removing a missing key should fail without writing any of its commands.
+#[test]
+fn write_batch_remove_non_existent_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let log = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log)?.len();
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value1").remove("key1").remove("key1");
+    assert!(store.write(batch).is_err());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(std::fs::metadata(&log)?.len(), len);
+
+    Ok(())
+}
+
+// A batch torn by a crash should be dropped as a whole on replay.
+#[test]
+fn torn_write_batch_is_dropped() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let log = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log)?.len();
+
+    let store = KvStore::open(temp_dir.path())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key1", "value1b").set("key2", "value2");
+    store.write(batch)?;
+    drop(store);
+    let contents = std::fs::read(&log)?;
+    std::fs::write(&log, &contents[..contents.len() - 3])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log)?.len(), len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Keys written by a batch should survive compaction.
+#[test]
+fn write_batch_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..10 {
+            batch.set(format!("key{}", key_id), format!("{}", iter));
+        }
+        store.write(batch)?;
+    }
+    store.wait_for_compaction()?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Conditional writes should only apply when the current value matches.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent(b"key1", b"value1")?);
+    assert!(!store.set_if_absent(b"key1", b"value2")?);
+    assert_eq!(store.get_bytes(b"key1")?, Some(b"value1".to_vec()));
+
+    assert!(!store.compare_and_swap(b"key1", Some(&b"value2"[..]), Some(&b"value3"[..]))?);
+    assert!(store.compare_and_swap(b"key1", Some(&b"value1"[..]), Some(&b"value3"[..]))?);
+    assert_eq!(store.get_bytes(b"key1")?, Some(b"value3".to_vec()));
+    assert!(!store.compare_and_swap(b"key2", Some(&b"value1"[..]), None)?);
+    assert!(store.compare_and_swap(b"key2", None, None)?);
+    assert!(!store.compare_and_swap(b"key1", None, None)?);
+
+    assert!(!store.remove_if_equals(b"key1", b"value1")?);
+    assert!(!store.remove_if_equals(b"key2", b"value3")?);
+    assert!(store.remove_if_equals(b"key1", b"value3")?);
+    assert_eq!(store.get_bytes(b"key1")?, None);
+
+    // Open from disk again and check persistent data.
+    assert!(store.set_if_absent(b"key2", b"value2")?);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"key1")?, None);
+    assert_eq!(store.get_bytes(b"key2")?, Some(b"value2".to_vec()));
+
+    Ok(())
+}
+
+// Concurrent compare-and-swap increments should never lose an update.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut increments = 0;
+                while increments < 50 {
+                    let current = store.get_bytes(b"counter")?.unwrap();
+                    let next: u64 = String::from_utf8(current.clone())?.parse::<u64>()? + 1;
+                    if store.compare_and_swap(
+                        b"counter",
+                        Some(&current),
+                        Some(next.to_string().as_bytes()),
+                    )? {
+                        increments += 1;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+
+    Ok(())
+}
+
+// Expired keys should be treated as absent, even after a restart.
+#[test]
+fn set_with_ttl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(b"session", b"token", Duration::from_millis(200))?;
+    store.set_with_ttl(b"long-session", b"token", Duration::from_secs(3600))?;
+    store.set_with_ttl(b"key", b"value", Duration::from_millis(200))?;
+    store.set_bytes(b"key", b"value")?;
+    assert_eq!(store.get_bytes(b"session")?, Some(b"token".to_vec()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get_bytes(b"session")?, None);
+    assert_eq!(store.get_bytes(b"long-session")?, Some(b"token".to_vec()));
+    assert_eq!(store.get_bytes(b"key")?, Some(b"value".to_vec()));
+    assert_eq!(store.scan_prefix("session").count(), 0);
+    assert!(store.remove_bytes(b"session").is_err());
+    assert!(store.set_if_absent(b"session", b"new-token")?);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"session")?, Some(b"new-token".to_vec()));
+    assert_eq!(store.get_bytes(b"long-session")?, Some(b"token".to_vec()));
+    assert_eq!(store.get_bytes(b"key")?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+// `expire` should keep the value of an existing key, and make it expire.
+#[test]
+fn expire() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(b"session", b"token")?;
+    assert!(store.expire(b"session", Duration::from_millis(200))?);
+    assert!(!store.expire(b"missing", Duration::from_millis(200))?);
+    assert_eq!(store.get_bytes(b"session")?, Some(b"token".to_vec()));
+    assert_eq!(store.get_bytes(b"missing")?, None);
+
+    // the expiry is persisted
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"session")?, Some(b"token".to_vec()));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get_bytes(b"session")?, None);
+
+    Ok(())
+}
+
+// Expired keys should count as wasted bytes, and be dropped by compaction.
+#[test]
+fn expired_keys_are_compacted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(10 * 1024)
+        .open(temp_dir.path())?;
+    let value = vec![b'x'; 1024];
+    for key_id in 0..20 {
+        store.set_with_ttl(
+            format!("key{}", key_id).as_bytes(),
+            &value,
+            Duration::from_millis(200),
+        )?;
+    }
+    store.set_with_ttl(b"kept", b"value", Duration::from_secs(3600))?;
+    assert_eq!(store.stats().wasted_bytes, 0);
+
+    thread::sleep(Duration::from_millis(300));
+    store.set_bytes(b"trigger", b"value")?;
+    store.wait_for_compaction()?;
+    assert_eq!(store.stats().keys, 2);
+    let log_size: u64 = log_files(temp_dir.path(), "log")
+        .iter()
+        .map(|name| std::fs::metadata(temp_dir.path().join(name)).unwrap().len())
+        .sum();
+    assert!(log_size < 1024, "log size {}", log_size);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"key0")?, None);
+    assert_eq!(store.get_bytes(b"kept")?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes(b"trigger")?, Some(b"value".to_vec()));
+
+    Ok(())
+}
+
+// A snapshot should keep seeing the values as of when it was taken.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3b").set("key4", "value4");
+    store.write(batch)?;
+    let later_snapshot = store.snapshot();
+    assert!(later_snapshot.seq() > snapshot.seq());
+    store.set("key1".to_owned(), "value1c".to_owned())?;
+
+    assert_eq!(snapshot.get("key0".to_owned())?, None);
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key4".to_owned())?, None);
+    assert_eq!(
+        collect_keys(snapshot.scan_prefix("key"))?,
+        vec!["key1", "key2", "key3"]
+    );
+    let values: Vec<_> = snapshot
+        .scan::<&str, _>(..)
+        .map(|entry| entry.map(|(_, value)| value))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        values,
+        vec![b"value1".to_vec(), b"value2".to_vec(), b"value3".to_vec()]
+    );
+
+    assert_eq!(
+        later_snapshot.get("key1".to_owned())?,
+        Some("value1b".to_owned())
+    );
+    assert_eq!(
+        collect_keys(later_snapshot.scan("key0".."key9"))?,
+        vec!["key0", "key1", "key3", "key4"]
+    );
+    assert_eq!(
+        later_snapshot.get("key3".to_owned())?,
+        Some("value3b".to_owned())
+    );
+
+    drop(snapshot);
+    assert_eq!(
+        later_snapshot.get("key1".to_owned())?,
+        Some("value1b".to_owned())
+    );
+    assert_eq!(store.get("key1".to_owned())?, Some("value1c".to_owned()));
+    assert_eq!(
+        collect_keys(store.scan_prefix("key"))?,
+        vec!["key0", "key1", "key3", "key4"]
+    );
+
+    Ok(())
+}
+
+// Compaction should keep the log files a live snapshot reads from, until the snapshot is dropped.
+#[test]
+fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+    }
+    store.wait_for_compaction()?;
+    let snapshot = store.snapshot();
+    for iter in 0..5 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("new{}", iter))?;
+        }
+        store.wait_for_compaction()?;
+    }
+    store.remove("key0".to_owned())?;
+    store.wait_for_compaction()?;
+
+    for key_id in 0..100 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some("old".to_owned())
+        );
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            (key_id != 0).then(|| "new4".to_owned())
+        );
+    }
+    assert_eq!(snapshot.scan_prefix("key").count(), 100);
+    let logs_with_snapshot = log_files(temp_dir.path(), "log").len();
+
+    drop(snapshot);
+    assert!(log_files(temp_dir.path(), "log").len() < logs_with_snapshot);
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("new4".to_owned()));
+
+    Ok(())
+}
+
+// A transaction should see its own writes, and apply all of them on commit.
+#[test]
+fn transaction_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "100".to_owned())?;
+    store.set("to".to_owned(), "0".to_owned())?;
+
+    let mut txn = store.transaction();
+    let from: u64 = String::from_utf8(txn.get("from")?.unwrap())?.parse()?;
+    let to: u64 = String::from_utf8(txn.get("to")?.unwrap())?.parse()?;
+    txn.set("from", (from - 30).to_string());
+    txn.set("to", (to + 30).to_string());
+    txn.set("temp", "value");
+    txn.remove("temp")?;
+    assert!(txn.remove("missing").is_err());
+    assert_eq!(txn.get("from")?, Some(b"70".to_vec()));
+    assert_eq!(store.get("from".to_owned())?, Some("100".to_owned()));
+    txn.commit()?;
+    assert_eq!(store.get("from".to_owned())?, Some("70".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("30".to_owned()));
+    assert_eq!(store.get("temp".to_owned())?, None);
+
+    // Dropping a transaction discards its writes.
+    let mut txn = store.transaction();
+    txn.remove("from")?;
+    drop(txn);
+    assert_eq!(store.get("from".to_owned())?, Some("70".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("70".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("30".to_owned()));
+
+    Ok(())
+}
+
+// Committing should fail if a key read by the transaction was changed by another writer.
+#[test]
+fn transaction_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut txn = store.transaction();
+    txn.get("key1")?;
+    txn.set("key2", "value2");
+    store.set("key1".to_owned(), "value1b".to_owned())?;
+    assert!(txn.commit().is_err());
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // reading a missing key which is then created is a conflict too
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("key3")?, None);
+    txn.set("key2", "value2");
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(txn.commit().is_err());
+
+    // writes to keys the transaction didn't read are not
+    let mut txn = store.transaction();
+    txn.get("key1")?;
+    txn.set("key2", "value2");
+    store.set("key3".to_owned(), "value3b".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A transaction whose commit marker never made it to disk should be ignored on replay.
+#[test]
+fn uncommitted_transaction_is_ignored() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut txn = store.transaction();
+    txn.set("key1", "value1b");
+    txn.set("key2", "value2");
+    txn.commit()?;
+    drop(store);
+
+    // the commit marker is the last record: 8 bytes of header, 4 bytes of variant and 8 bytes of transaction id
+    let log = temp_dir.path().join("1.log");
+    let contents = std::fs::read(&log)?;
+    std::fs::write(&log, &contents[..contents.len() - 20])?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Namespaces should hold separate sets of keys, which persist across restarts.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    let orders = store.namespace("orders")?;
+    store.set("key1".to_owned(), "default".to_owned())?;
+    users.set("key1".to_owned(), "users".to_owned())?;
+    orders.set("key2".to_owned(), "orders".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(users.get("key1".to_owned())?, Some("users".to_owned()));
+    assert_eq!(orders.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(users.stats().keys, 1);
+    assert_eq!(
+        store.namespaces(),
+        vec![
+            "default".to_owned(),
+            "users".to_owned(),
+            "orders".to_owned()
+        ]
+    );
+
+    // opening an existing namespace returns a handle to the same keys
+    let users_again = store.namespace("users")?;
+    assert_eq!(
+        users_again.get("key1".to_owned())?,
+        Some("users".to_owned())
+    );
+    users.remove("key1".to_owned())?;
+    assert_eq!(users_again.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    users.set("key3".to_owned(), "users".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop((store, users, users_again, orders));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    let users = store.namespace("users")?;
+    assert_eq!(users.get("key1".to_owned())?, None);
+    assert_eq!(users.get("key3".to_owned())?, Some("users".to_owned()));
+    let orders = store.namespace("orders")?;
+    assert_eq!(orders.get("key2".to_owned())?, Some("orders".to_owned()));
+
+    Ok(())
+}
+
+// Dropping a namespace should remove its keys, and only its keys.
+#[test]
+fn drop_namespace() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    store.set("key1".to_owned(), "default".to_owned())?;
+    users.set("key1".to_owned(), "users".to_owned())?;
+
+    assert!(store.drop_namespace("default").is_err());
+    assert!(store.drop_namespace("missing").is_err());
+    store.drop_namespace("users")?;
+    assert_eq!(store.namespaces(), vec!["default".to_owned()]);
+    assert_eq!(users.get("key1".to_owned())?, None);
+    assert!(users.set("key1".to_owned(), "users".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+
+    drop(users);
+
+    // a namespace created again under the same name starts empty
+    let users = store.namespace("users")?;
+    assert_eq!(users.get("key1".to_owned())?, None);
+    users.set("key2".to_owned(), "users".to_owned())?;
+
+    // Open from disk again and check persistent data.
+    drop((store, users));
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    assert_eq!(users.get("key1".to_owned())?, None);
+    assert_eq!(users.get("key2".to_owned())?, Some("users".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should keep the keys of every namespace apart, including in hint files.
+#[test]
+fn namespace_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    for iter in 0..50 {
+        for key_id in 0..10 {
+            store.set(format!("key{}", key_id), format!("default{}", iter))?;
+            users.set(format!("key{}", key_id), format!("users{}", iter))?;
+        }
+    }
+    store.wait_for_compaction()?;
+    assert!(!log_files(temp_dir.path(), "log").contains(&"1.log".to_owned()));
+    assert!(!log_files(temp_dir.path(), "hint").is_empty());
+
+    // Open from disk again and check persistent data.
+    drop((store, users));
+    let store = KvStore::open(temp_dir.path())?;
+    let users = store.namespace("users")?;
+    assert_eq!(store.namespaces().len(), 2);
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("default49".to_owned())
+        );
+        assert_eq!(
+            users.get(format!("key{}", key_id))?,
+            Some("users49".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+fn json_value(id: usize) -> String {
+    format!(
+        r#"{{"id": {}, "name": "user{}", "roles": ["reader", "writer"], "description": "{}"}}"#,
+        id,
+        id,
+        "a rather verbose description ".repeat(10)
+    )
+}
+
+// Compressed records should shrink the logs, and read back the same values.
+#[test]
+fn compression() -> Result<()> {
+    for &compression in &[Compression::Lz4, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::builder()
+            .compression(compression)
+            .open(temp_dir.path())?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), json_value(key_id))?;
+        }
+        assert!(store.stats().compression_ratio > 2.0);
+        let log_len = std::fs::metadata(temp_dir.path().join("1.log"))?.len();
+        let raw_len: usize = (0..100).map(|key_id| json_value(key_id).len()).sum();
+        assert!(log_len < raw_len as u64 / 2);
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(json_value(key_id))
+            );
+        }
+
+        // Open from disk again and check persistent data.
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.stats().compression_ratio, 1.0);
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(json_value(key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Logs holding records of different compressions should replay and compact correctly.
+#[test]
+fn mixed_compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let compressions = [
+        Compression::None,
+        Compression::Lz4,
+        Compression::Zstd,
+        Compression::None,
+    ];
+    for (iter, &compression) in compressions.iter().enumerate() {
+        let store = KvStore::builder()
+            .compression(compression)
+            .open(temp_dir.path())?;
+        for key_id in 0..10 {
+            store.set(format!("key{}-{}", iter, key_id), json_value(key_id))?;
+        }
+    }
+
+    let store = KvStore::builder()
+        .compression(Compression::Zstd)
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for iter in 0..compressions.len() {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}-{}", iter, key_id))?,
+                Some(json_value(key_id))
+            );
+        }
+    }
+    // overwrite a single key until compaction kicks in
+    for iter in 0..20 {
+        store.set("pad".to_owned(), format!("{}{}", iter, "x".repeat(1000)))?;
+    }
+    store.wait_for_compaction()?;
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..compressions.len() {
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}-{}", iter, key_id))?,
+                Some(json_value(key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn files_contain(dir: &std::path::Path, needle: &[u8]) -> Result<bool> {
+    for entry in std::fs::read_dir(dir)? {
+        let contents = std::fs::read(entry?.path())?;
+        if contents
+            .windows(needle.len())
+            .any(|window| window == needle)
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Encrypted stores should never hold plaintext on disk, and fail to open with the wrong key.
+#[test]
+fn encryption() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .encryption_key(1, [7; 32])
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("secret-key".to_owned(), format!("secret-value{}", iter))?;
+    }
+    store.wait_for_compaction()?;
+    assert!(!log_files(temp_dir.path(), "hint").is_empty());
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value99".to_owned())
+    );
+    drop(store);
+    assert!(!files_contain(temp_dir.path(), b"secret-key")?);
+    assert!(!files_contain(temp_dir.path(), b"secret-value")?);
+
+    let err = KvStore::builder()
+        .encryption_key(1, [8; 32])
+        .open(temp_dir.path())
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("Wrong encryption key"));
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(format!("{:?}", err).contains("no encryption key"));
+
+    // Open from disk again and check persistent data.
+    let store = KvStore::builder()
+        .encryption_key(1, [7; 32])
+        .open(temp_dir.path())?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value99".to_owned())
+    );
+
+    Ok(())
+}
+
+// Compacting with a new key should re-encrypt the whole store, so that the previous key is no longer needed.
+#[test]
+fn encryption_key_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "plaintext".to_owned())?;
+    drop(store);
+
+    // an unencrypted store gets encrypted by compacting it with a key, which must be asked for
+    let err = KvStore::builder()
+        .encryption_key(1, [1; 32])
+        .open(temp_dir.path())
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("migrate_to_encryption"));
+    let store = KvStore::builder()
+        .encryption_key(1, [1; 32])
+        .migrate_to_encryption(true)
+        .open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    drop(store);
+    assert!(!files_contain(temp_dir.path(), b"plaintext")?);
+
+    let store = KvStore::builder()
+        .encryption_key(2, [2; 32])
+        .decryption_key(1, [1; 32])
+        .open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("plaintext".to_owned()));
+    store.compact()?;
+    drop(store);
+
+    let err = KvStore::builder()
+        .encryption_key(1, [1; 32])
+        .open(temp_dir.path())
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("key 2"));
+    let store = KvStore::builder()
+        .encryption_key(2, [2; 32])
+        .open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("plaintext".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Records written without encryption should never be read from an encrypted store, since anybody could append them.
+#[test]
+fn encryption_rejects_plaintext_records() -> Result<()> {
+    use std::io::Write;
+
+    let plaintext_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(plaintext_dir.path())?;
+    store.set("key1".to_owned(), "forged".to_owned())?;
+    drop(store);
+    // the records of the log, without its file header
+    let forged_records = std::fs::read(plaintext_dir.path().join("1.log"))?.split_off(8);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .encryption_key(1, [1; 32])
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join("1.log"))?;
+    log.write_all(&forged_records)?;
+    drop(log);
+
+    for migrate_to_encryption in [false, true] {
+        let err = KvStore::builder()
+            .encryption_key(1, [1; 32])
+            .migrate_to_encryption(migrate_to_encryption)
+            .open(temp_dir.path())
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("not encrypted"));
+    }
+
+    // the same goes for a store once it was migrated to encryption
+    let store = KvStore::builder()
+        .encryption_key(1, [1; 32])
+        .migrate_to_encryption(true)
+        .open(plaintext_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("forged".to_owned()));
+    store.compact()?;
+    drop(store);
+    // the log taking new writes, since compacted logs are loaded from their hint files
+    let new_writes_log = log_files(plaintext_dir.path(), "log")
+        .into_iter()
+        .max_by_key(|name| name.trim_end_matches(".log").parse::<u64>().unwrap())
+        .unwrap();
+    let mut log = std::fs::OpenOptions::new()
+        .append(true)
+        .open(plaintext_dir.path().join(new_writes_log))?;
+    log.write_all(&forged_records)?;
+    drop(log);
+    let err = KvStore::builder()
+        .encryption_key(1, [1; 32])
+        .migrate_to_encryption(true)
+        .open(plaintext_dir.path())
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains("not encrypted"));
+
+    Ok(())
+}
+
+// Only one process at a time should be able to open a store for writing, and readers should share the store.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let pid = std::process::id().to_string();
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(format!("{:?}", err).contains(&pid));
+    let err = KvStore::builder()
+        .read_only(true)
+        .open(temp_dir.path())
+        .unwrap_err();
+    assert!(format!("{:?}", err).contains(&pid));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains(pid.as_str()));
+
+    // the lock is released with the last handle
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+
+    let reader1 = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    let reader2 = KvStore::builder().read_only(true).open(temp_dir.path())?;
+    assert_eq!(reader2.get("key1".to_owned())?, Some("value1".to_owned()));
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(format!("{:?}", err).contains("read-only"));
+    drop((reader1, reader2));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+fn dir_listing(dir: &std::path::Path) -> Result<Vec<(String, u64)>> {
+    let mut listing = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        listing.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            entry.metadata()?.len(),
+        ));
+    }
+    listing.sort();
+    Ok(listing)
+}
+
+// A store opened with `open_read_only` should only replay the logs, leaving every file as it was.
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(KvStore::open_read_only(temp_dir.path().join("missing")).is_err());
+    assert!(dir_listing(temp_dir.path())?.is_empty());
+
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.wait_for_compaction()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    // half of a record, which a writable open would truncate
+    let log = temp_dir
+        .path()
+        .join(log_files(temp_dir.path(), "log").last().unwrap());
+    let mut contents = std::fs::read(&log)?;
+    contents.extend_from_within(..10);
+    std::fs::write(&log, contents)?;
+    let listing = dir_listing(temp_dir.path())?;
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(store.set("key3".to_owned(), "value3".to_owned()).is_err());
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.compact().is_err());
+    assert!(store.namespace("users").is_err());
+    drop(store);
+    assert_eq!(dir_listing(temp_dir.path())?, listing);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+    assert_eq!(dir_listing(temp_dir.path())?, listing);
+
+    Ok(())
+}
+
+// Files which the manifest doesn't list, left behind by a crash, should be ignored and removed on open.
+#[test]
+fn orphans_are_removed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_threshold(1024)
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.wait_for_compaction()?;
+    store.remove("key1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert!(!log_files(temp_dir.path(), "log").contains(&"1.log".to_owned()));
+
+    // a previous generation compaction didn't get to remove, an interrupted compaction,
+    // and a generation whose writes never made it into the manifest
+    let orphans = ["1.log", "98.tmp", "98.hint.tmp", "99.log", "MANIFEST.tmp"];
+    for orphan in &orphans {
+        std::fs::write(temp_dir.path().join(orphan), b"garbage")?;
+    }
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    drop(store);
+    for orphan in &orphans {
+        assert!(temp_dir.path().join(orphan).exists());
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    for orphan in &orphans {
+        assert!(!temp_dir.path().join(orphan).exists());
+    }
+    assert!(temp_dir.path().join("MANIFEST").exists());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// The commands of the first versions of kvs, which wrote them to the log with plain bincode.
+#[derive(Serialize)]
+enum BaselineCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+fn baseline_set(key: &str, value: &str) -> BaselineCommand {
+    BaselineCommand::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+// Writes a log file the way the first versions of kvs did: no header, no framing, no manifest.
+fn write_legacy_log(
+    dir: &std::path::Path,
+    generation: u64,
+    commands: &[BaselineCommand],
+) -> Result<()> {
+    let mut file = std::fs::File::create(dir.join(format!("{}.log", generation)))?;
+    for command in commands {
+        bincode::serialize_into(&mut file, command)?;
+    }
+    Ok(())
+}
+
+// Log files written before the file header existed should stay readable, and be rewritten by `migrate`.
+#[test]
+fn migrate_legacy_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_log(
+        temp_dir.path(),
+        1,
+        &[
+            baseline_set("key1", "value1"),
+            baseline_set("key2", "stale"),
+            baseline_set("key4", "value4"),
+            BaselineCommand::Remove {
+                key: "key4".to_owned(),
+            },
+        ],
+    )?;
+    write_legacy_log(temp_dir.path(), 2, &[baseline_set("key2", "value2")])?;
+    // a write torn by a crash: the versions which wrote the log stopped reading there
+    let mut torn = bincode::serialize(&baseline_set("key5", "value5"))?;
+    torn.truncate(torn.len() - 3);
+    let mut legacy_log = std::fs::read(temp_dir.path().join("2.log"))?;
+    legacy_log.extend_from_slice(&torn);
+    std::fs::write(temp_dir.path().join("2.log"), &legacy_log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+    assert_eq!(store.get("key5".to_owned())?, None);
+    // new records go to a new generation, never into the legacy files
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(std::fs::read(temp_dir.path().join("2.log"))?, legacy_log);
+
+    assert_eq!(store.migrate()?, 2);
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(!temp_dir.path().join("2.log").exists());
+    assert_eq!(store.migrate()?, 0);
+    for log in log_files(temp_dir.path(), "log") {
+        assert!(std::fs::read(temp_dir.path().join(log))?.starts_with(b"\x89KVS"));
+    }
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs migrate` should rewrite a legacy store into the current format.
+#[test]
+fn cli_migrate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_log(
+        temp_dir.path(),
+        1,
+        &[
+            baseline_set("key1", "value1"),
+            baseline_set("key2", "value2"),
+            baseline_set("key3", "value3"),
+        ],
+    )?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value2").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// Opening a store written in a newer format should fail clearly, instead of misreading it.
+#[test]
+fn unknown_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut contents = std::fs::read(&log)?;
+    contents[4..8].copy_from_slice(&99u32.to_le_bytes());
+    std::fs::write(&log, &contents)?;
+    let err = KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(format!("{:?}", err).contains("Unsupported format version 99"));
+
+    // a failed open leaves no lock file behind, when there was none
+    std::fs::remove_file(temp_dir.path().join("LOCK"))?;
+    KvStore::open(temp_dir.path()).unwrap_err();
+    assert!(!temp_dir.path().join("LOCK").exists());
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use kvs::{NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+
+const JOBS: usize = 100;
+
+// Runs `JOBS` jobs on a pool of 4 threads, checking that they all ran.
+fn run_jobs<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..JOBS {
+        let counter = Arc::clone(&counter);
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..JOBS {
+        receiver.recv_timeout(Duration::from_secs(10))?;
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), JOBS);
+    Ok(())
+}
+
+// Runs more panicking jobs than the pool has threads, then checks that the pool still runs jobs.
+fn survive_panics<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(4)?;
+    for _ in 0..8 {
+        pool.spawn(|| panic!("panicking job"));
+    }
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..JOBS {
+        let sender = sender.clone();
+        pool.spawn(move || sender.send(()).unwrap());
+    }
+    for _ in 0..JOBS {
+        receiver.recv_timeout(Duration::from_secs(10))?;
+    }
+    Ok(())
+}
+
+#[test]
+fn naive_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_runs_jobs() -> Result<()> {
+    run_jobs::<RayonThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_survives_panics() -> Result<()> {
+    survive_panics::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_survives_panics() -> Result<()> {
+    survive_panics::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_survives_panics() -> Result<()> {
+    survive_panics::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_needs_threads() {
+    assert!(SharedQueueThreadPool::new(0).is_err());
+}